@@ -1,23 +1,82 @@
 use std::rc::Rc;
 use std::cell::{RefCell};
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use crate::datatypes::*;
 use std::cmp;
 use crate::parseutils::*;
 use crate::builders::svgbuilder::*;
+use crate::errors::{ErrorKind, FoggyError};
 
 static FONT_SIZE:f32=4.0; // in SVG user coordinates. This is the size from baseline to baseline
 static FONT_INTERLINE:f32=6.0; // in SVG user coordinates.
 
 static LIFELINE_WIDTH:f32=0.1;
 static ACTIVATION_BOX_WIDTH:f32=4.0;
+static ACTIVATION_MIN_HEIGHT:f32=4.0;
+static ACTIVATION_STYLE:&str="fill:#ffffff;fill-opacity:1;stroke:#2e282a;stroke-width:0.3;stroke-opacity:1";
 
 static PARTICIPANT_BOX_WIDTH:f32=28.0;
 static PARTICIPANT_BOX_HEIGHT:f32=18.0;
+static PARTICIPANT_STYLE:&str="fill:#ede7d9;fill-opacity:1;stroke:#2e282a;stroke-width:0.26458332;stroke-opacity:1";
+static ICON_RADIUS:f32=5.0; // boundary, control and entity are circles, with their name below
+static ICON_HEIGHT:f32=19.0;
+static ACTOR_HEIGHT:f32=22.0;
+static DATABASE_HEIGHT:f32=21.0;
+static DATABASE_RY:f32=3.0;
+static COLLECTIONS_OFFSET:f32=2.0;
+static ICON_STYLE:&str="fill:#ede7d9;fill-opacity:1;stroke:#2e282a;stroke-width:0.4;stroke-opacity:1";
+static ICON_LINE_STYLE:&str="fill:none;stroke:#2e282a;stroke-width:0.4;stroke-linecap:round;stroke-opacity:1";
+
+static ARROW_LINE_STYLE:&str="fill:none;stroke:#2e282a;stroke-width:0.40000001;stroke-linecap:butt;stroke-linejoin:miter;stroke-miterlimit:4;stroke-dasharray:none;stroke-opacity:1";
+static ARROW_DOTTED_LINE_STYLE:&str="fill:none;stroke:#2e282a;stroke-width:0.40000001;stroke-linecap:butt;stroke-linejoin:miter;stroke-miterlimit:4;stroke-dasharray:1.2,0.8;stroke-opacity:1";
+static ARROW_HEAD_STYLE:&str="fill:#857970;fill-opacity:1;stroke:#2e282a;stroke-width:0.40000001;stroke-linecap:butt;stroke-linejoin:round;stroke-miterlimit:4;stroke-dasharray:none;stroke-opacity:1";
+static BORDER_MARGIN:f32=2.0; // messages from or to the edge of the diagram start or end that far from it
+static CONSTRAINT_OFFSET:f32=4.0; // between the dimension line and the messages
+static CONSTRAINT_TICK:f32=1.5;
+static CONSTRAINT_STYLE:&str="fill:none;stroke:#2e282a;stroke-width:0.3;stroke-opacity:1";
+static SELF_MESSAGE_WIDTH:f32=10.0; // loop of the messages to self
+static SELF_MESSAGE_HEIGHT:f32=6.0;
+static ARROW_DECOR_SIZE:f32=1.5; // radius of the 'o' circle, half width of the 'x' cross
+
+static DESTRUCTION_CROSS_SIZE:f32=3.0;
+static DESTRUCTION_CROSS_STYLE:&str="fill:none;stroke:#2e282a;stroke-width:0.5;stroke-opacity:1";
 
 static CHAR_AVERAGE_RATIO:f32=0.6; // for test block size estimations
 static CHAR_AVERAGE_RATIO_BOLD:f32=0.67; // for test block size estimations
 
+static TEXT_STYLE:&str="font-style:normal;font-weight:normal;font-size:3.8px;line-height:1.25;font-family:sans-serif;letter-spacing:0px;word-spacing:0px;fill:#000000;fill-opacity:1;";
+static TEXT_STYLE_BOLD:&str="font-style:normal;font-weight:bold;font-size:3.8px;line-height:1.25;font-family:sans-serif;letter-spacing:0px;word-spacing:0px;fill:#000000;fill-opacity:1;";
+
+static FRAGMENT_HEADER_HEIGHT:f32=8.0; // tab of the frame, and label of each section
+static FRAGMENT_BOTTOM_MARGIN:f32=4.0;
+static FRAGMENT_PADDING:f32=10.0; // between the frame and the lifelines of the participants involved
+static FRAGMENT_NESTING_STEP:f32=2.0;
+static FRAGMENT_FRAME_STYLE:&str="fill:none;stroke:#2e282a;stroke-width:0.3;stroke-opacity:1";
+static FRAGMENT_TAB_STYLE:&str="fill:#ede7d9;fill-opacity:1;stroke:#2e282a;stroke-width:0.3;stroke-opacity:1";
+static FRAGMENT_SEPARATOR_STYLE:&str="fill:none;stroke:#2e282a;stroke-width:0.3;stroke-dasharray:2,1;stroke-opacity:1";
+
+
+static NOTE_PADDING:f32=2.0;
+static NOTE_MARGIN:f32=3.0; // between the note and the lifeline
+static NOTE_OVERHANG:f32=8.0; // notes over several participants go a bit further than their lifelines
+static NOTE_FOLD:f32=3.0;
+static NOTE_STYLE:&str="fill:#fbf7e4;fill-opacity:1;stroke:#2e282a;stroke-width:0.3;stroke-opacity:1";
+
+static DELAY_HEIGHT:f32=12.0;
+static DELAY_STYLE:&str="fill:none;stroke:#568259;stroke-width:0.5;stroke-dasharray:1,1.5;stroke-opacity:1";
+static SPACE_HEIGHT:f32=12.0; // for '|||'
+static SEPARATOR_HEIGHT:f32=12.0;
+static SEPARATOR_STYLE:&str="fill:none;stroke:#2e282a;stroke-width:0.3;stroke-opacity:1";
+static SEPARATOR_LABEL_STYLE:&str="fill:#ede7d9;fill-opacity:1;stroke:#2e282a;stroke-width:0.3;stroke-opacity:1";
+static BACKGROUND_STYLE:&str="fill:none;stroke:#ffffff;stroke-width:1;stroke-opacity:1";
+
+static TITLE_HEIGHT:f32=12.0;
+static TITLE_STYLE:&str="font-style:normal;font-weight:bold;font-size:5px;line-height:1.25;font-family:sans-serif;letter-spacing:0px;word-spacing:0px;fill:#000000;fill-opacity:1;";
+
+static BOX_LABEL_HEIGHT:f32=8.0; // boxes are higher than the participants, for their label
+static BOX_PADDING:f32=4.0; // between the participants and the innermost box
+static BOX_NESTING_STEP:f32=3.0; // outer boxes are a bit larger than the inner ones
+static BOX_DEFAULT_COLOR:&str="#f4f1ea";
 
 static PARTICIPANTS_TYPES: [&'static str;7] = [
     "participant",
@@ -34,8 +93,8 @@ pub struct SequenceDiagramBuilder{
 }
 
 
-//handles left and right directions
-fn create_arrow(x_origin:f32, x_target:f32, y:f32, id:Option<&str>)->Element{
+//handles left and right directions. The head, and the optional decoration, are at the target end
+fn create_arrow(x_origin:f32, x_target:f32, y:f32, line_style:&str, arrow_style:&str, decor:Option<&str>, id:Option<&str>)->Element{
     // <g
     //    id="g1017">
     //   <path
@@ -49,27 +108,62 @@ fn create_arrow(x_origin:f32, x_target:f32, y:f32, id:Option<&str>)->Element{
     // </g>
 
     let mut group=create_group(id);
+    // 1 when the arrow goes right, -1 when it goes left
+    let d=match x_origin < x_target{
+        true => 1.0,
+        false => -1.0,
+    };
+    // the circle is drawn at the end of the arrow, the head stops before it
+    let tip=match decor{
+        Some("round") => x_target-d*2.0*ARROW_DECOR_SIZE,
+        _ => x_target,
+    };
     //horizontal line
-    let path1=create_path(format!("m {},{} h {}", x_origin, y, x_target-x_origin).as_str(), 
-        "fill:none;stroke:#2e282a;stroke-width:0.40000001;stroke-linecap:butt;stroke-linejoin:miter;stroke-miterlimit:4;stroke-dasharray:none;stroke-opacity:1", 
-        None);
-    let path2 = create_path(match x_origin < x_target{
-            true => format!("m {},{} 4,1 -4,1 z", x_target-4.0, y-1.0), 
-            false => format!("m {},{} -4,1 4,1 z", x_target+4.0, y-1.0)
-        }.as_str(),
-        "fill:#857970;fill-opacity:1;stroke:#2e282a;stroke-width:0.40000001;stroke-linecap:butt;stroke-linejoin:round;stroke-miterlimit:4;stroke-dasharray:none;stroke-opacity:1", 
+    let path1=create_path(format!("m {},{} h {}", x_origin, y, tip-x_origin).as_str(),
+        match line_style{
+            "dotted" => ARROW_DOTTED_LINE_STYLE,
+            _ => ARROW_LINE_STYLE,
+        },
         None);
+    group.push(Rc::new(RefCell::new(path1)));
 
+    // the lost message cross replaces the head
+    if decor != Some("cross"){
+        group.push(Rc::new(RefCell::new(create_arrow_head(tip, y, d, arrow_style))));
+        if arrow_style.starts_with("bidirectional"){
+            group.push(Rc::new(RefCell::new(create_arrow_head(x_origin, y, -d, arrow_style))));
+        }
+    }
 
-
-    group.push(Rc::new(RefCell::new(path1)));
-    group.push(Rc::new(RefCell::new(path2)));
+    match decor{
+        Some("cross") => {
+            let c=x_target-d*ARROW_DECOR_SIZE;
+            let r=ARROW_DECOR_SIZE;
+            group.push(Rc::new(RefCell::new(create_path(
+                format!("m {},{} l {},{} m 0,{} l {},{}", c-r, y-r, 2.0*r, 2.0*r, -2.0*r, -2.0*r, 2.0*r).as_str(),
+                ARROW_LINE_STYLE, None))));
+        },
+        Some("round") => {
+            group.push(Rc::new(RefCell::new(create_circle(x_target-d*ARROW_DECOR_SIZE, y, ARROW_DECOR_SIZE, ARROW_LINE_STYLE, None))));
+        },
+        _ => (),
+    }
     return group;
 }
 
-
-
-
+// Head whose tip is at x, pointing to the right if d is 1, to the left if it's -1.
+// Fine heads are open, half heads only have the part above (top) or below (bottom) the line
+fn create_arrow_head(x:f32, y:f32, d:f32, arrow_style:&str)->Element{
+    let l=-4.0*d;
+    return match arrow_style{
+        "fine"|"bidirectional-fine" => create_path(format!("m {},{} l {},1 {},1", x+l, y-1.0, -l, l).as_str(), ARROW_LINE_STYLE, None),
+        "top" => create_path(format!("m {},{} l {},1 h {} z", x+l, y-1.0, -l, l).as_str(), ARROW_HEAD_STYLE, None),
+        "bottom" => create_path(format!("m {},{} l {},-1 h {} z", x+l, y+1.0, -l, l).as_str(), ARROW_HEAD_STYLE, None),
+        "top-fine" => create_path(format!("m {},{} l {},1", x+l, y-1.0, -l).as_str(), ARROW_LINE_STYLE, None),
+        "bottom-fine" => create_path(format!("m {},{} l {},-1", x+l, y+1.0, -l).as_str(), ARROW_LINE_STYLE, None),
+        _ => create_path(format!("m {},{} {},1 {},1 z", x+l, y-1.0, -l, l).as_str(), ARROW_HEAD_STYLE, None),
+    };
+}
 
 
 
@@ -138,12 +232,26 @@ impl SequenceDiagramBuilder{
             return (0.0,0.0);
             //TODO panic ?
         }
+        // the number of autonumber is written before the text
+        let number_width=match elt.get_attr("number"){
+            Some(number) => Some((number.chars().count()+1) as f32*FONT_SIZE*match elt.get_attr("number-style").as_deref(){
+                Some("bold") => CHAR_AVERAGE_RATIO_BOLD,
+                _ => CHAR_AVERAGE_RATIO,
+            }),
+            None => None,
+        };
         if let ElementContent::Tree(content) = &elt.content{
-            match content.children.first(){ // text is not mandatory on arrows
-                None => return (6.0,6.0),
-                Some(e) =>{
-                    let (w, h) = Self::estimate_text_size(Rc::clone(e));
-                    return (w+4.0, h+2.0);
+            match (content.children.first(), number_width){ // text is not mandatory on arrows
+                (None, None) => return (6.0,6.0),
+                (None, Some(number_width)) => return (number_width+4.0, FONT_INTERLINE+2.0),
+                (Some(e), _) =>{
+                    // the message is in a text element
+                    let text=match e.borrow().is_tree(){
+                        true => children(e).first().cloned().unwrap_or_else(|| Rc::clone(e)),
+                        false => Rc::clone(e),
+                    };
+                    let (w, h) = Self::estimate_text_size(text);
+                    return (w+number_width.unwrap_or(0.0)+4.0, h+2.0);
                 }
             }
         }
@@ -151,15 +259,18 @@ impl SequenceDiagramBuilder{
         //TODO panic ?
     }
 
-    pub fn generate_svg (&mut self, description: &[Rc<RefCell<Element>>])->Result<String, String>{
+    pub fn generate_svg (&mut self, description: &[Rc<RefCell<Element>>])->Result<String, FoggyError>{
         if description.len()!=2{
-            return Err(String::from("Bad format for input data"));
+            return Err(FoggyError::new(ErrorKind::InvalidDiagram(String::from("Bad format for input data"))));
         }
         //First element shall be the header
         let header:Rc<RefCell<Element>>=Rc::clone(&description[0]);
         //Second element shall be the header
         let content:Rc<RefCell<Element>>=Rc::clone(&description[1]);
-
+        if !header.borrow().is_tree() || header.borrow().get_tag() != "sequencediagram:header"
+            || !content.borrow().is_tree() || content.borrow().get_tag() != "sequencediagram:content"{
+            return Err(FoggyError::new(ErrorKind::InvalidDiagram(String::from("Bad format for input data"))));
+        }
         // Build a list of all participants
         // I need a list ordered by alias for fast lookup: participant + y
         let mut participants_map:HashMap<String, (Rc<RefCell<Element>>, ParticipantExtraInfo)>=HashMap::new(); 
@@ -167,6 +278,20 @@ impl SequenceDiagramBuilder{
         let mut participants_list:Vec<Rc<RefCell<Element>>>=vec![];
         let mut index:isize=0;
 
+        // with 'hide unlinked', participants that are not in the sequence are not drawn at all
+        let hide_unlinked=header.borrow().get_attr("unlinked") == Some(String::from("hide"));
+        let mut linked:HashSet<String>=HashSet::new();
+        recurse_element_tree(Rc::clone(&content), |e, _d|{
+            let elt = e.borrow();
+            if elt.is_tree(){
+                for attr in &["origin", "target", "participant", "to"]{
+                    if let Some(alias)=elt.get_attr(attr){
+                        linked.insert(alias);
+                    }
+                }
+            }
+        });
+
         //1st pass on sequence : check list of participants in header
         recurse_element_tree(Rc::clone(&header), 
             |e, d|{
@@ -174,7 +299,8 @@ impl SequenceDiagramBuilder{
                 // if element is a participant definition
                 if elt.is_tree()
                     && PARTICIPANTS_TYPES.contains(&elt.get_tag().as_str())
-                    && elt.get_attr("alias") != None{
+                    && elt.get_attr("alias") != None
+                    && (!hide_unlinked || linked.contains(&elt.get_attr("alias").unwrap())){
 
                     participants_list.push(Rc::clone(&e));
                     participants_map.insert(elt.get_attr("alias").unwrap(), 
@@ -184,18 +310,52 @@ impl SequenceDiagramBuilder{
             }
         );
 
-        let mut document_height:f32=PARTICIPANT_BOX_HEIGHT; // start value
         // 1st pass on content, check
         // - text length between participants
-        // - height of document
+        // - notes next to lifelines: participant, side and width
+        let mut notes:Vec<(String, String, f32)>=vec![];
+        // - messages from or to the borders, for the margins
+        let mut left_border_width:f32=0.0;
+        let participants_count=index;
+        // - constraints, written on the left of the messages
+        let mut constraint_width:f32=0.0;
         recurse_element_tree(Rc::clone(&content), 
             |e, _d|{
                 let elt = e.borrow();
+                if elt.is_tree() && elt.get_tag() == "constraint"{
+                    if let Some(QueryResult::Text(text)) = elt.get("text/text()"){
+                        constraint_width=f32::max(constraint_width, text.chars().count() as f32*CHAR_AVERAGE_RATIO*FONT_SIZE);
+                    }
+                }
+                if elt.is_tree() && elt.get_tag() == "note" && elt.get_attr("to") == None{
+                    if let (Some(alias), Some(position)) = (elt.get_attr("participant"), elt.get_attr("position")){
+                        let (w, _h) = note_size(&note_lines(&e));
+                        notes.push((alias, position, w));
+                    }
+                }
                 // if element is a participant definition
                 if elt.is_tree()
                     && elt.get_tag() == "arrow"{
                     let origin=elt.get_attr("origin");
                     let target=elt.get_attr("target");
+                    match (elt.get_attr("border").as_deref(), origin.as_ref().or(target.as_ref())){
+                        (Some("left"), _) => {
+                            let (w,_h) = Self::estimate_arrow_dimensions(Rc::clone(&e));
+                            left_border_width=f32::max(left_border_width, w);
+                            return;
+                        },
+                        // the label is next to the participant, there is room if it's not the last one
+                        (Some("right"), Some(alias)) => {
+                            let (w,_h) = Self::estimate_arrow_dimensions(Rc::clone(&e));
+                            if let Some((_, info)) = participants_map.get_mut(alias){
+                                if info.index == participants_count-1{
+                                    info.right_gap=f32::max(info.right_gap, w);
+                                }
+                            }
+                            return;
+                        },
+                        _ => (),
+                    }
                     if origin==None || target==None{
                         return;
                     }
@@ -215,27 +375,53 @@ impl SequenceDiagramBuilder{
                         // arrow to self : same index
                         // arrow between neighbour participants: index differ by +-1
                         // other arrows are longer and don't dictate the space between neighbours
-                        if origin_info.index == target_index
-                            || origin_info.index == target_index+1
+                        if origin_info.index == target_index{
+                            // the loop and its label are on the right of the lifeline
+                            let (w,_h) = Self::estimate_arrow_dimensions(Rc::clone(&e));
+                            origin_info.right_gap =f32::max(origin_info.right_gap, SELF_MESSAGE_WIDTH+w+ACTIVATION_BOX_WIDTH);
+                        }
+                        else if origin_info.index == target_index+1
                             || origin_info.index == target_index-1{
-                            let (w,h) = Self::estimate_arrow_dimensions(Rc::clone(&e));
+                            let (w,_h) = Self::estimate_arrow_dimensions(Rc::clone(&e));
                             origin_info.right_gap =f32::max(origin_info.right_gap, w);
-                            document_height+=h;
-
-                        }
-                        else{
-                            let (_,h) = Self::estimate_arrow_dimensions(Rc::clone(&e));
-                            document_height+=h;
                         }
                     }
 
                 }
             }
         );
-        document_height+=4.0; //for good measure
+
+        // make room for the notes, in the gap on their side
+        let mut left_margin:f32 = f32::max(40.0, left_border_width+BORDER_MARGIN+ACTIVATION_BOX_WIDTH);
+        left_margin=f32::max(left_margin, constraint_width+CONSTRAINT_OFFSET+2.0*CONSTRAINT_TICK);
+        {
+            let mut aliases:Vec<(isize, String)>=participants_map.iter()
+                .map(|(alias, (_, info))| (info.index, alias.clone())).collect();
+            aliases.sort();
+            for (alias, position, w) in notes{
+                let index=match participants_map.get(&alias){
+                    Some((_, info)) => info.index as usize,
+                    None => continue,
+                };
+                let (left, right)=match position.as_str(){
+                    "left" => (w+2.0*NOTE_MARGIN, 0.0),
+                    "right" => (0.0, w+2.0*NOTE_MARGIN),
+                    _ => (w/2.0+NOTE_MARGIN, w/2.0+NOTE_MARGIN),
+                };
+                if index == 0{
+                    left_margin=f32::max(left_margin, left);
+                }
+                else if let Some((_, info))=participants_map.get_mut(&aliases[index-1].1){
+                    info.right_gap=f32::max(info.right_gap, left);
+                }
+                if let Some((_, info))=participants_map.get_mut(&alias){
+                    info.right_gap=f32::max(info.right_gap, right);
+                }
+            }
+        }
 
         // compute document width
-        let mut x = 40.0;
+        let mut x = left_margin;
         recurse_element_tree(Rc::clone(&header), 
             |e, _d|{
                 let elt = e.borrow();
@@ -252,7 +438,68 @@ impl SequenceDiagramBuilder{
                 }
             }
         );
-        let document_width:f32=x+40.0;
+        let title=match header.borrow().get("title/text()"){
+            Some(QueryResult::Text(text)) => Some(text),
+            _ => None,
+        };
+        let mut document_width:f32=x+40.0;
+        if let Some(text)=&title{
+            document_width=f32::max(document_width, text_width(text)*5.0/FONT_SIZE+20.0);
+        }
+
+        // the content starts below the highest participant, the others are drawn at the same top
+        let header_height=participants_map.values()
+            .filter(|(p, _)| p.borrow().get_attr("created").is_none())
+            .map(|(p, _)| participant_size(&p.borrow().get_tag()).1)
+            .fold(PARTICIPANT_BOX_HEIGHT, f32::max);
+
+        // Lay out the content first, its height gives the length of the lifelines
+        let content_g=Rc::new(RefCell::new(create_group(Some("content"))));
+        // activation bars go over the lifelines, but under the messages
+        let activations_g=Rc::new(RefCell::new(create_group(Some("activations"))));
+        content_g.borrow_mut().push(Rc::clone(&activations_g));
+        let mut layout=ContentLayout{
+            participants: &participants_map,
+            width: document_width,
+            y: header_height,
+            fragment_level: 0,
+            activations: HashMap::new(),
+            activations_svg: activations_g,
+            pending_creations: vec![],
+            created: HashMap::new(),
+            destroyed: HashMap::new(),
+            messages: HashMap::new(),
+        };
+        Self::draw_sequence(&children(&content), &mut layout, &content_g)?;
+        layout.place_pending_creations();
+        // participants still active at the end are active until the end
+        layout.y+=ACTIVATION_MIN_HEIGHT;
+        let y_end=layout.y;
+        for bars in layout.activations.values(){
+            for (left, y_start, bar) in bars{
+                draw_activation_bar(bar, *left, *y_start, y_end);
+            }
+        }
+        let lifelines_end:f32=layout.y+4.0; //for good measure
+        // participants are drawn again at the end of their lifeline, unless 'hide footbox'
+        let footbox=header.borrow().get_attr("footbox") != Some(String::from("hide"));
+        let mut document_height:f32=lifelines_end;
+        if footbox{
+            document_height+=participants_map.values()
+                .map(|(p, _)| participant_size(&p.borrow().get_tag()).1)
+                .fold(PARTICIPANT_BOX_HEIGHT, f32::max)+2.0;
+        }
+        // boxes around participants have their label above the participants, and go a bit below the footbox
+        let diagram_bottom:f32=document_height;
+        let box_levels=box_depth(&header);
+        if box_levels>0{
+            document_height+=BOX_PADDING+(box_levels-1) as f32*BOX_NESTING_STEP;
+        }
+        let diagram_top:f32=box_levels as f32*BOX_LABEL_HEIGHT+if title.is_some() {TITLE_HEIGHT} else {0.0};
+        document_height+=diagram_top;
+        // where participants are drawn, if they are created, and where their lifeline stops
+        let created=std::mem::take(&mut layout.created);
+        let destroyed=std::mem::take(&mut layout.destroyed);
 
         let mut xml_stack:Vec<Rc<RefCell<Element>>>=vec![];
 
@@ -261,13 +508,38 @@ impl SequenceDiagramBuilder{
         // iteratively build svg content
         // Build header
         let document_root=Rc::new(RefCell::new(create_svg(document_width, document_height)));
-        xml_stack.push(Rc::clone(&document_root));
+        // the title is centered above the diagram
+        if let Some(text)=&title{
+            let mut text_elt=create_text(document_width/2.0, TITLE_HEIGHT-4.0, TITLE_STYLE, Some("title"));
+            text_elt.push_attribute("text-anchor","middle");
+            if let Some(QueryResult::Elt(title_elt))=header.borrow().get("title/"){
+                set_source_attribute(&mut text_elt, &title_elt.borrow().source);
+            }
+            text_elt.push(Rc::new(RefCell::new(Element::new_str("tspan", text))));
+            document_root.borrow_mut().push(Rc::new(RefCell::new(text_elt)));
+        }
+        // the diagram is moved down to make room for the title and the box labels
+        let diagram_g=match diagram_top>0.0{
+            true => {
+                let group=Rc::new(RefCell::new(create_translate_group(0.0, diagram_top, None)));
+                document_root.borrow_mut().push(Rc::clone(&group));
+                group
+            },
+            false => Rc::clone(&document_root),
+        };
+        xml_stack.push(Rc::clone(&diagram_g));
+        if box_levels>0{
+            // behind everything else
+            let boxes_g=Rc::new(RefCell::new(create_group(Some("boxes"))));
+            draw_boxes(&header, &participants_map, 0, box_levels, diagram_bottom, &boxes_g);
+            diagram_g.borrow_mut().push(boxes_g);
+        }
         {
             let header_g=Rc::new(RefCell::new(create_group(Some("header"))));
             xml_stack.push(Rc::clone(&header_g));
-            document_root.borrow_mut().push(Rc::clone(&header_g));
+            diagram_g.borrow_mut().push(Rc::clone(&header_g));
         }
-        // Add participant and boxes
+        // Add participants
         recurse_element_tree(Rc::clone(&header), 
             |e, _d|{
                 let elt = e.borrow();
@@ -279,90 +551,743 @@ impl SequenceDiagramBuilder{
                 if PARTICIPANTS_TYPES.contains(&elt.get_tag().as_str())
                     && elt.get_attr("alias") != None{
 
-                    if let Some ((_, info)) = participants_map.get_mut(&elt.get_attr("alias").unwrap()){
-                        let rect=Rc::new(RefCell::new(create_rect(info.x-PARTICIPANT_BOX_WIDTH/2.0, 0.0,
-                            PARTICIPANT_BOX_WIDTH,
-                            PARTICIPANT_BOX_HEIGHT, 
-                            "fill:#ede7d9;fill-opacity:1;stroke:#2e282a;stroke-width:0.26458332;stroke-opacity:1",
-                            Some(0.8), None)));
-
-                        xml_stack.last().unwrap().borrow_mut().push(rect);
-
-                        if let Some(QueryResult::Text(text)) = elt.get("name/text()"){
-                            let mut text_elt=create_text(info.x, PARTICIPANT_BOX_HEIGHT/2.0-4.0, 
-                                "font-style:normal;font-weight:normal;font-size:3.8px;line-height:1.25;font-family:sans-serif;letter-spacing:0px;word-spacing:0px;fill:#000000;fill-opacity:1;", 
-                                None);
-                            text_elt.push_attribute("text-anchor","middle");
-                            text_elt.push(Rc::new(RefCell::new(Element::new_str("tspan", &text))));
-                            xml_stack.last().unwrap().borrow_mut().push(Rc::new(RefCell::new(text_elt)));
-                        }
+                    let alias=elt.get_attr("alias").unwrap();
+                    if let Some ((_, info)) = participants_map.get_mut(&alias){
+                        // one group per participant, that points to its declaration
+                        let mut group=create_group(None);
+                        set_source_attribute(&mut group, &elt.source);
 
-                        let path= create_path(format!("m {},{} v {}", info.x, PARTICIPANT_BOX_HEIGHT, document_height-PARTICIPANT_BOX_HEIGHT).as_str(),
+                        let top=created.get(&alias).cloned().unwrap_or(0.0);
+                        let bottom=destroyed.get(&alias).cloned().unwrap_or(lifelines_end);
+                        let height=draw_participant_shape(&mut group, &elt, info.x, top);
+
+                        // from the bottom of the shape
+                        let path= create_path(format!("m {},{} v {}", info.x, top+height, bottom-top-height).as_str(),
                             "stroke:#568259;stroke-width:0.5;stroke-linecap:butt;stroke-linejoin:miter;stroke-miterlimit:4;stroke-dasharray:none;stroke-opacity:1",
                             None);
-                        xml_stack.last().unwrap().borrow_mut().push(Rc::new(RefCell::new(path)));
+                        group.push(Rc::new(RefCell::new(path)));
+                        // destroyed participants end with their cross
+                        if footbox && !destroyed.contains_key(&alias){
+                            draw_participant_shape(&mut group, &elt, info.x, lifelines_end);
+                        }
+                        xml_stack.last().unwrap().borrow_mut().push(Rc::new(RefCell::new(group)));
                     }
                 }
-                else if elt.get_tag() == "box"{
-                    panic!("not implemented");
-                    //TODO no support for boxes yet
-
-                }
             }
         );
         // remove header group from stack
         xml_stack.pop();
 
-        {
-            let content_g=Rc::new(RefCell::new(create_group(Some("content"))));
-            xml_stack.push(Rc::clone(&content_g));
-            document_root.borrow_mut().push(Rc::clone(&content_g));
+        diagram_g.borrow_mut().push(content_g);
+        return Ok(document_root.borrow().to_xml());
+    }
+
+    // Draw content elements from top to bottom, layout.y is moved down accordingly
+    fn draw_sequence(elements:&[Rcc<Element>], layout:&mut ContentLayout, svg:&Rcc<Element>)->Result<(), FoggyError>{
+        for e in elements{
+            if !e.borrow().is_tree(){
+                continue;
+            }
+            let tag=e.borrow().get_tag();
+            // a created participant is drawn with the message to it, or right away
+            if tag != "arrow" && tag != "create"{
+                layout.place_pending_creations();
+            }
+            match tag.as_str(){
+                "arrow" => Self::draw_arrow(e, layout, svg),
+                "fragment" => Self::draw_fragment(e, layout, svg)?,
+                "activate" => layout.activate(e),
+                "deactivate" => layout.deactivate(e),
+                "create" => if let Some(alias)=e.borrow().get_attr("participant"){
+                    layout.pending_creations.push(alias);
+                },
+                "destroy" => Self::draw_destruction(e, layout, svg),
+                "note" => Self::draw_note(e, layout, svg),
+                "delay" => Self::draw_delay(e, layout, svg),
+                "space" => layout.y+=e.borrow().get_attr("height")
+                    .and_then(|h| h.parse::<f32>().ok())
+                    .unwrap_or(SPACE_HEIGHT),
+                "separator" => Self::draw_separator(e, layout, svg),
+                "constraint" => Self::draw_constraint(e, layout, svg),
+                // section labels are drawn by their fragment
+                _ => (),
+            }
         }
-        let mut y= PARTICIPANT_BOX_HEIGHT;
-        recurse_element_tree(Rc::clone(&content), 
-            |e, _d|{
-                let elt = e.borrow();
-                // if element is a participant definition
-                if elt.is_tree()
-                    && elt.get_tag() == "arrow"{
-                    let origin=elt.get_attr("origin");
-                    let target=elt.get_attr("target");
-                    if origin==None || target==None{
-                        return;
-                    }
-                    let origin_str = origin.unwrap();
-                    let target_str = target.unwrap();
+        return Ok(());
+    }
 
-                    let (_w,h) = Self::estimate_arrow_dimensions(Rc::clone(&e));
+    fn draw_arrow(e:&Rcc<Element>, layout:&mut ContentLayout, svg:&Rcc<Element>){
+        let elt = e.borrow();
+        // found and lost messages have no participant on the border side
+        let border_x=match elt.get_attr("border").as_deref(){
+            Some("left") => Some(BORDER_MARGIN),
+            Some("right") => Some(layout.width-BORDER_MARGIN),
+            _ => None,
+        };
+        let (origin, target)=(elt.get_attr("origin").unwrap_or_default(), elt.get_attr("target").unwrap_or_default());
+        let (origin_x, target_x)=match (layout.x_of(&origin), layout.x_of(&target), border_x){
+            (Some(origin_x), Some(target_x), _) => (origin_x, target_x),
+            (Some(origin_x), None, Some(border_x)) => (origin_x, border_x),
+            (None, Some(target_x), Some(border_x)) => (border_x, target_x),
+            _ => return,
+        };
+        if origin == target{
+            return Self::draw_self_message(e, layout, svg);
+        }
+        let (_w,mut h) = Self::estimate_arrow_dimensions(Rc::clone(&e));
+        let y=layout.y;
+
+        // messages start and end on the side of the activation bars
+        let (mut target_x, origin_x) = match origin_x <= target_x{
+            true => (target_x-layout.bars_left(&target), origin_x+layout.bars_right(&origin)),
+            false => (target_x+layout.bars_right(&target), origin_x-layout.bars_left(&origin)),
+        };
+
+        // the message that creates a participant points to the middle of its box
+        let creation=layout.pending_creations.iter().position(|a| *a == target);
+        if let Some(i)=creation{
+            layout.pending_creations.remove(i);
+            let (width, height)=layout.size_of(&target);
+            h=f32::max(h, height/2.0+2.0);
+            layout.created.insert(target.clone(), y+h-height/2.0);
+            target_x+=match origin_x <= target_x{
+                true => -width/2.0,
+                false => width/2.0,
+            };
+        }
 
-                    let target_x = 
-                        if let Some ((_, target_info)) = participants_map.get(target_str.as_str()){
-                            target_info.x
-                        }
-                        else{ 0.0 };
+        let mut arrow= create_arrow(origin_x, target_x, y+h,
+            &elt.get_attr("line-style").unwrap_or_default(),
+            &elt.get_attr("arrow-style").unwrap_or_default(),
+            elt.get_attr("decor").as_deref(),
+            None);
+        set_source_attribute(&mut arrow, &elt.source);
 
-                    let origin_x = 
-                        if let Some ((_, origin_info)) = participants_map.get(origin_str.as_str()){
-                            origin_info.x 
-                        }
-                        else {0.0};
+        if let Some(text_elt)=arrow_label(&elt, f32::min(origin_x, target_x)+4.0, y+h-1.0){
+            arrow.push(Rc::new(RefCell::new(text_elt)));
+        }
+        if let Some(id)=elt.get_attr("id"){
+            layout.messages.insert(id, (y+h, f32::min(origin_x, target_x)));
+        }
+        svg.borrow_mut().push(Rc::new(RefCell::new(arrow)));
+        layout.y+=h;
+        if creation.is_some(){
+            layout.y+=layout.size_of(&target).1/2.0;
+        }
+    }
 
-                    let arrow= create_arrow(origin_x, target_x, y+h, None);
-                    xml_stack.last().unwrap().borrow_mut().push(Rc::new(RefCell::new(arrow)));
+    // Message to self: a loop on the right of the lifeline, with its label beside it
+    fn draw_self_message(e:&Rcc<Element>, layout:&mut ContentLayout, svg:&Rcc<Element>){
+        let elt = e.borrow();
+        let alias=elt.get_attr("origin").unwrap_or_default();
+        let x=match layout.x_of(&alias){
+            Some(x) => x+layout.bars_right(&alias),
+            None => return,
+        };
+        let (_w, h) = Self::estimate_arrow_dimensions(Rc::clone(&e));
+        let top=layout.y+4.0;
+        let bottom=top+SELF_MESSAGE_HEIGHT;
+
+        // the way back has the head and the style of the message
+        let mut arrow= create_arrow(x+SELF_MESSAGE_WIDTH, x, bottom,
+            &elt.get_attr("line-style").unwrap_or_default(),
+            &elt.get_attr("arrow-style").unwrap_or_default(),
+            elt.get_attr("decor").as_deref(),
+            None);
+        set_source_attribute(&mut arrow, &elt.source);
+        let path=create_path(format!("m {},{} h {} v {}", x, top, SELF_MESSAGE_WIDTH, SELF_MESSAGE_HEIGHT).as_str(),
+            match elt.get_attr("line-style").as_deref(){
+                Some("dotted") => ARROW_DOTTED_LINE_STYLE,
+                _ => ARROW_LINE_STYLE,
+            },
+            None);
+        arrow.push(Rc::new(RefCell::new(path)));
+
+        if let Some(text_elt)=arrow_label(&elt, x+SELF_MESSAGE_WIDTH+2.0, top+FONT_SIZE/2.0){
+            arrow.push(Rc::new(RefCell::new(text_elt)));
+        }
+        if let Some(id)=elt.get_attr("id"){
+            layout.messages.insert(id, (top, x));
+        }
+        svg.borrow_mut().push(Rc::new(RefCell::new(arrow)));
+        layout.y=f32::max(bottom+2.0, layout.y+h+2.0);
+    }
 
-                    if let Some(QueryResult::Text(text)) = elt.get("text/text()"){
-                        let mut text_elt=create_text(f32::min(origin_x, target_x)+4.0, y+h-1.0, 
-                            "font-style:normal;font-weight:normal;font-size:3.8px;line-height:1.25;font-family:sans-serif;letter-spacing:0px;word-spacing:0px;fill:#000000;fill-opacity:1;", 
-                            None);
-                        text_elt.push(Rc::new(RefCell::new(Element::new_str("tspan", &text))));
-                        xml_stack.last().unwrap().borrow_mut().push(Rc::new(RefCell::new(text_elt)));
+    // Constraint between two messages: a vertical dimension line on their left, from one to the other,
+    // with the text on its left
+    fn draw_constraint(e:&Rcc<Element>, layout:&mut ContentLayout, svg:&Rcc<Element>){
+        let elt = e.borrow();
+        let ends=(elt.get_attr("start").and_then(|id| layout.messages.get(&id).cloned()),
+            elt.get_attr("end").and_then(|id| layout.messages.get(&id).cloned()));
+        let ((y1, x1), (y2, x2))=match ends{
+            (Some(start), Some(end)) => (start, end),
+            _ => return,
+        };
+        let x=f32::min(x1, x2)-CONSTRAINT_OFFSET;
+        let (top, bottom)=(f32::min(y1, y2), f32::max(y1, y2));
+        let t=CONSTRAINT_TICK;
+
+        let mut group=create_group(None);
+        set_source_attribute(&mut group, &elt.source);
+        // ticks at both ends, and the line with a head on each of them
+        let path=create_path(
+            format!("m {},{} h {} m {},0 v {} m {},0 h {} m {},{} l {},{} {},{} m 0,{} l {},{} {},{}",
+                x-t, top, 2.0*t, -t, bottom-top, -t, 2.0*t,
+                -2.0*t, -2.0*t, t, 2.0*t, t, -2.0*t,
+                -(bottom-top-4.0*t), -t, -2.0*t, -t, 2.0*t).as_str(),
+            CONSTRAINT_STYLE, None);
+        group.push(Rc::new(RefCell::new(path)));
+
+        if let Some(QueryResult::Text(text)) = elt.get("text/text()"){
+            let mut text_elt=create_text(x-t, (top+bottom)/2.0+FONT_SIZE/3.0, TEXT_STYLE, None);
+            text_elt.push_attribute("text-anchor","end");
+            text_elt.push(Rc::new(RefCell::new(Element::new_str("tspan", &text))));
+            group.push(Rc::new(RefCell::new(text_elt)));
+        }
+        svg.borrow_mut().push(Rc::new(RefCell::new(group)));
+    }
+
+    // Notes are next to a lifeline, or over one or several of them
+    fn draw_note(e:&Rcc<Element>, layout:&mut ContentLayout, svg:&Rcc<Element>){
+        let elt = e.borrow();
+        let alias=elt.get_attr("participant").unwrap_or_default();
+        let x=match layout.x_of(&alias){
+            Some(x) => x,
+            None => return,
+        };
+        let lines=note_lines(e);
+        let (mut w, h)=note_size(&lines);
+        let left=match (elt.get_attr("position").unwrap_or_default().as_str(), elt.get_attr("to").and_then(|a| layout.x_of(&a))){
+            ("left", _) => x-layout.bars_left(&alias)-NOTE_MARGIN-w,
+            ("right", _) => x+layout.bars_right(&alias)+NOTE_MARGIN,
+            (_, Some(x_to)) => {
+                let (x_min, x_max)=(f32::min(x, x_to), f32::max(x, x_to));
+                let span=x_max-x_min+2.0*NOTE_OVERHANG;
+                let left=f32::min(x_min-NOTE_OVERHANG, (x_min+x_max-w)/2.0);
+                w=f32::max(w, span);
+                left
+            },
+            (_, None) => x-w/2.0,
+        };
+        let top=layout.y+NOTE_MARGIN;
+
+        let mut group=create_group(None);
+        set_source_attribute(&mut group, &elt.source);
+        let shape=match elt.get_attr("shape").unwrap_or_default().as_str(){
+            "rnote" => create_rect(left, top, w, h, NOTE_STYLE, None, None),
+            "hnote" => create_path(
+                format!("m {},{} l {},{} h {} l {},{} l {},{} h {} z", left, top+h/2.0, NOTE_PADDING, -h/2.0, w-2.0*NOTE_PADDING,
+                    NOTE_PADDING, h/2.0, -NOTE_PADDING, h/2.0, -(w-2.0*NOTE_PADDING)).as_str(),
+                NOTE_STYLE, None),
+            // folded corner
+            _ => create_path(
+                format!("m {},{} h {} l {},{} v {} h {} z m {},{} v {} h {}", left, top, w-NOTE_FOLD, NOTE_FOLD, NOTE_FOLD, h-NOTE_FOLD, -w,
+                    w-NOTE_FOLD, 0.0, NOTE_FOLD, NOTE_FOLD).as_str(),
+                NOTE_STYLE, None),
+        };
+        group.push(Rc::new(RefCell::new(shape)));
+
+        for (i, line) in lines.iter().enumerate(){
+            let mut text_elt=create_text(left+NOTE_PADDING, top+NOTE_PADDING+(i+1) as f32*FONT_INTERLINE-1.5, TEXT_STYLE, None);
+            for (text, style) in line{
+                let mut tspan=create_tspan(None, None, if style.len()>0 {Some(style)} else {None}, None);
+                tspan.push(Rc::new(RefCell::new(Element::str(text))));
+                text_elt.push(Rc::new(RefCell::new(tspan)));
+            }
+            group.push(Rc::new(RefCell::new(text_elt)));
+        }
+        svg.borrow_mut().push(Rc::new(RefCell::new(group)));
+        layout.y=top+h;
+    }
+
+    // Lifelines are dotted during a delay, with the text in the middle
+    fn draw_delay(e:&Rcc<Element>, layout:&mut ContentLayout, svg:&Rcc<Element>){
+        let elt = e.borrow();
+        let text=match elt.get("text/text()"){
+            Some(QueryResult::Text(text)) => Some(text),
+            _ => None,
+        };
+        let height=DELAY_HEIGHT+if text.is_some() {FONT_INTERLINE} else {0.0};
+        let y=layout.y;
+
+        let mut group=create_group(None);
+        set_source_attribute(&mut group, &elt.source);
+        for x in layout.lifelines_at(){
+            // hide the lifeline, and draw the dotted one over it
+            for style in &[BACKGROUND_STYLE, DELAY_STYLE]{
+                group.push(Rc::new(RefCell::new(create_path(format!("m {},{} v {}", x, y, height).as_str(), style, None))));
+            }
+        }
+        if let Some(text)=text{
+            let mut text_elt=create_text(layout.width/2.0, y+height/2.0+1.5, TEXT_STYLE, None);
+            text_elt.push_attribute("text-anchor","middle");
+            text_elt.push(Rc::new(RefCell::new(Element::new_str("tspan", &text))));
+            group.push(Rc::new(RefCell::new(text_elt)));
+        }
+        svg.borrow_mut().push(Rc::new(RefCell::new(group)));
+        layout.y+=height;
+    }
+
+    // A double line across the diagram, with the label in a box in the middle
+    fn draw_separator(e:&Rcc<Element>, layout:&mut ContentLayout, svg:&Rcc<Element>){
+        let elt = e.borrow();
+        let y=layout.y+SEPARATOR_HEIGHT/2.0;
+
+        let mut group=create_group(None);
+        set_source_attribute(&mut group, &elt.source);
+        group.push(Rc::new(RefCell::new(create_path(
+            format!("m 0,{} h {} m 0,1.5 h {}", y-0.75, layout.width, -layout.width).as_str(),
+            SEPARATOR_STYLE, None))));
+        if let Some(QueryResult::Text(text))=elt.get("text/text()"){
+            let w=text_width(&text)+6.0;
+            group.push(Rc::new(RefCell::new(create_rect((layout.width-w)/2.0, y-FONT_INTERLINE/2.0-1.0, w, FONT_INTERLINE+2.0,
+                SEPARATOR_LABEL_STYLE, None, None))));
+            let mut text_elt=create_text(layout.width/2.0, y+1.5, TEXT_STYLE_BOLD, None);
+            text_elt.push_attribute("text-anchor","middle");
+            text_elt.push(Rc::new(RefCell::new(Element::new_str("tspan", &text))));
+            group.push(Rc::new(RefCell::new(text_elt)));
+        }
+        svg.borrow_mut().push(Rc::new(RefCell::new(group)));
+        layout.y+=SEPARATOR_HEIGHT;
+    }
+
+    // The lifeline ends with a cross
+    fn draw_destruction(e:&Rcc<Element>, layout:&mut ContentLayout, svg:&Rcc<Element>){
+        let elt = e.borrow();
+        let alias=match elt.get_attr("participant"){
+            Some(alias) => alias,
+            None => return,
+        };
+        let x=match layout.x_of(&alias){
+            Some(x) => x,
+            None => return,
+        };
+        let y=layout.y+DESTRUCTION_CROSS_SIZE+1.0;
+        // close the bars that are still open
+        if let Some(bars)=layout.activations.remove(&alias){
+            for (left, y_start, bar) in bars{
+                draw_activation_bar(&bar, left, y_start, y);
+            }
+        }
+        let size=DESTRUCTION_CROSS_SIZE;
+        let mut cross=create_path(
+            format!("m {},{} l {},{} m 0,{} l {},{}", x-size, y-size, 2.0*size, 2.0*size, -2.0*size, -2.0*size, 2.0*size).as_str(),
+            DESTRUCTION_CROSS_STYLE, None);
+        set_source_attribute(&mut cross, &elt.source);
+        svg.borrow_mut().push(Rc::new(RefCell::new(cross)));
+        layout.destroyed.insert(alias, y);
+        layout.y=y+size;
+    }
+
+    // A fragment is a frame around its content, with a tab for its type.
+    // Sections are separated by dashed lines, each one with its label.
+    fn draw_fragment(e:&Rcc<Element>, layout:&mut ContentLayout, svg:&Rcc<Element>)->Result<(), FoggyError>{
+        let elt = e.borrow();
+        let fragment_type=elt.get_attr("type").unwrap_or_default();
+
+        // nested frames are a bit narrower than their parent
+        let padding=f32::max(FRAGMENT_PADDING-layout.fragment_level as f32*FRAGMENT_NESTING_STEP, 2.0);
+        let (x_min, x_max)=layout.span_of(e);
+        let left=x_min-padding;
+        let width=x_max-x_min+2.0*padding;
+        let y_top=layout.y;
+
+        let mut group=create_group(None);
+        set_source_attribute(&mut group, &elt.source);
+        let group=Rc::new(RefCell::new(group));
+        // the frame is drawn once its height is known, but it stays behind the content
+        let frame=Rc::new(RefCell::new(create_group(None)));
+        group.borrow_mut().push(Rc::clone(&frame));
+        svg.borrow_mut().push(Rc::clone(&group));
+
+        let tab_width=text_width(&fragment_type)+6.0;
+        layout.fragment_level+=1;
+        for (i, section) in children(e).iter().enumerate(){
+            if !section.borrow().is_tree() || section.borrow().get_tag() != "section"{
+                continue;
+            }
+            let label=match section.borrow().get("label/text()"){
+                Some(QueryResult::Text(text)) => Some(format!("[{}]", text)),
+                _ => None,
+            };
+
+            // the first label goes next to the tab, the others under their separator
+            let label_x=if i==0 {left+tab_width+2.0} else {left+2.0};
+            if i>0{
+                frame.borrow_mut().push(Rc::new(RefCell::new(create_path(
+                    format!("m {},{} h {}", left, layout.y, width).as_str(),
+                    FRAGMENT_SEPARATOR_STYLE, None))));
+            }
+            if let Some(text)=label{
+                let mut text_elt=create_text(label_x, layout.y+FRAGMENT_HEADER_HEIGHT-2.5, TEXT_STYLE, None);
+                text_elt.push(Rc::new(RefCell::new(Element::new_str("tspan", &text))));
+                frame.borrow_mut().push(Rc::new(RefCell::new(text_elt)));
+            }
+            layout.y+=FRAGMENT_HEADER_HEIGHT;
+
+            Self::draw_sequence(&children(section), layout, &group)?;
+        }
+        layout.fragment_level-=1;
+        layout.y+=FRAGMENT_BOTTOM_MARGIN;
+
+        let mut frame=frame.borrow_mut();
+        frame.push(Rc::new(RefCell::new(create_rect(left, y_top, width, layout.y-y_top, FRAGMENT_FRAME_STYLE, None, None))));
+        // tab with the fragment type, in the top left corner
+        frame.push(Rc::new(RefCell::new(create_path(
+            format!("m {},{} h {} v {} l -2,2 h {} z", left, y_top, tab_width, FRAGMENT_HEADER_HEIGHT-2.0, -(tab_width-2.0)).as_str(),
+            FRAGMENT_TAB_STYLE, None))));
+        let mut text_elt=create_text(left+2.0, y_top+FRAGMENT_HEADER_HEIGHT-2.5, TEXT_STYLE_BOLD, None);
+        text_elt.push(Rc::new(RefCell::new(Element::new_str("tspan", &fragment_type))));
+        frame.push(Rc::new(RefCell::new(text_elt)));
+        return Ok(());
+    }
+}
+
+// Where we are while drawing the content
+struct ContentLayout<'a>{
+    participants: &'a HashMap<String, (Rcc<Element>, ParticipantExtraInfo)>,
+    width: f32,
+    y: f32,
+    // nesting level of the fragment being drawn
+    fragment_level: usize,
+    // open activation bars by participant: left side, top and placeholder in activations_svg
+    activations: HashMap<String, Vec<(f32, f32, Rcc<Element>)>>,
+    activations_svg: Rcc<Element>,
+    // participants created but not drawn yet, then where they are drawn, and where they are destroyed
+    pending_creations: Vec<String>,
+    created: HashMap<String, f32>,
+    destroyed: HashMap<String, f32>,
+    // messages with an id: y of the line and left end, for the constraints
+    messages: HashMap<String, (f32, f32)>,
+}
+
+impl<'a> ContentLayout<'a>{
+    fn x_of(&self, alias:&str)->Option<f32>{
+        self.participants.get(alias).map(|(_, info)| info.x)
+    }
+
+    // Horizontal extent of the participants involved in the arrows and notes below e.
+    // All the participants if there is no arrow
+    fn span_of(&self, e:&Rcc<Element>)->(f32, f32){
+        let mut xs:Vec<f32>=vec![];
+        recurse_element_tree(Rc::clone(e), |c, _d|{
+            let elt=c.borrow();
+            if elt.is_tree() && (elt.get_tag() == "arrow" || elt.get_tag() == "note"){
+                for attr in &["origin", "target", "participant", "to"]{
+                    if let Some(x)=elt.get_attr(attr).and_then(|a| self.x_of(&a)){
+                        xs.push(x);
                     }
-                    y+=h;
                 }
             }
-        );
-        return Ok(document_root.borrow().to_xml());
+        });
+        if xs.is_empty(){
+            xs=self.participants.values().map(|(_, info)| info.x).collect();
+        }
+        if xs.is_empty(){
+            return (40.0, 40.0);
+        }
+        let x_min=xs.iter().cloned().fold(f32::INFINITY, f32::min);
+        let x_max=xs.iter().cloned().fold(f32::NEG_INFINITY, f32::max);
+        return (x_min, x_max);
+    }
+
+    fn size_of(&self, alias:&str)->(f32, f32){
+        match self.participants.get(alias){
+            Some((p, _)) => participant_size(&p.borrow().get_tag()),
+            None => (PARTICIPANT_BOX_WIDTH, PARTICIPANT_BOX_HEIGHT),
+        }
+    }
+
+    // x of the lifelines drawn at this point: not created later, not destroyed yet
+    fn lifelines_at(&self)->Vec<f32>{
+        let mut xs:Vec<f32>=self.participants.iter()
+            .filter(|(alias, (p, _))| !self.destroyed.contains_key(*alias)
+                && (p.borrow().get_attr("created").is_none() || self.created.contains_key(*alias)))
+            .map(|(_, (_, info))| info.x)
+            .collect();
+        xs.sort_by(|a, b| a.partial_cmp(b).unwrap());
+        return xs;
+    }
+
+    // Participants created without a message to them are drawn where we are
+    fn place_pending_creations(&mut self){
+        for alias in std::mem::take(&mut self.pending_creations){
+            self.y+=2.0;
+            let height=self.size_of(&alias).1;
+            self.created.insert(alias, self.y);
+            self.y+=height;
+        }
+    }
+
+    // The bar is drawn when it's closed, but its place is kept now so nested bars are drawn over it
+    fn activate(&mut self, e:&Rcc<Element>){
+        let elt=e.borrow();
+        let alias=match elt.get_attr("participant"){
+            Some(alias) => alias,
+            None => return,
+        };
+        let x=match self.x_of(&alias){
+            Some(x) => x,
+            None => return,
+        };
+        let bars=self.activations.entry(alias).or_insert_with(Vec::new);
+        // nested bars are shifted to the right
+        let left=x-ACTIVATION_BOX_WIDTH/2.0+bars.len() as f32*ACTIVATION_BOX_WIDTH/2.0;
+        let mut group=create_group(None);
+        set_source_attribute(&mut group, &elt.source);
+        let group=Rc::new(RefCell::new(group));
+        self.activations_svg.borrow_mut().push(Rc::clone(&group));
+        bars.push((left, self.y, group));
+    }
+
+    fn deactivate(&mut self, e:&Rcc<Element>){
+        let alias=match e.borrow().get_attr("participant"){
+            Some(alias) => alias,
+            None => return,
+        };
+        if let Some((left, y_start, bar))=self.activations.get_mut(&alias).and_then(|bars| bars.pop()){
+            // give some room to bars without any message
+            self.y=f32::max(self.y, y_start+ACTIVATION_MIN_HEIGHT);
+            draw_activation_bar(&bar, left, y_start, self.y);
+        }
+    }
+
+    // distance between the lifeline and the left or right side of the active bars
+    fn bars_left(&self, alias:&str)->f32{
+        match self.activations.get(alias){
+            Some(bars) if bars.len()>0 => ACTIVATION_BOX_WIDTH/2.0,
+            _ => 0.0,
+        }
+    }
+    fn bars_right(&self, alias:&str)->f32{
+        match self.activations.get(alias){
+            Some(bars) if bars.len()>0 => bars.len() as f32*ACTIVATION_BOX_WIDTH/2.0,
+            _ => 0.0,
+        }
+    }
+}
+
+// Text of a message, after its number if autonumber is on
+fn arrow_label(elt:&Element, x:f32, y:f32)->Option<Element>{
+    let text=match elt.get("text/text()"){
+        Some(QueryResult::Text(text)) => Some(text),
+        _ => None,
+    };
+    let number=elt.get_attr("number");
+    if text.is_none() && number.is_none(){
+        return None;
+    }
+    let mut text_elt=create_text(x, y, TEXT_STYLE, None);
+    if let Some(number)=number{
+        let style=match elt.get_attr("number-style").as_deref(){
+            Some("bold") => Some("font-weight:bold"),
+            _ => None,
+        };
+        let mut tspan=create_tspan(None, None, style, None);
+        let separator=if text.is_some() {" "} else {""};
+        tspan.push(Rc::new(RefCell::new(Element::str(&format!("{}{}", number, separator)))));
+        text_elt.push(Rc::new(RefCell::new(tspan)));
     }
+    if let Some(text)=text{
+        text_elt.push(Rc::new(RefCell::new(Element::new_str("tspan", &text))));
+    }
+    return Some(text_elt);
+}
+
+// Number of nested boxes
+fn box_depth(e:&Rcc<Element>)->usize{
+    children(e).iter()
+        .filter(|c| c.borrow().is_tree() && c.borrow().get_tag() == "box")
+        .map(|c| 1+box_depth(c))
+        .max().unwrap_or(0)
+}
+
+// Boxes are the columns of their participants, with the label on top.
+// level is the nesting level of the boxes in e, from 0 for the outermost ones.
+// Boxes without any drawn participant are not drawn
+fn draw_boxes(e:&Rcc<Element>, participants:&HashMap<String, (Rcc<Element>, ParticipantExtraInfo)>,
+    level:usize, levels:usize, bottom:f32, svg:&Rcc<Element>){
+    for child in children(e){
+        let elt=child.borrow();
+        if !elt.is_tree() || elt.get_tag() != "box"{
+            continue;
+        }
+        let mut xs:Vec<f32>=vec![];
+        recurse_element_tree(Rc::clone(&child), |c, _d|{
+            if let Some((_, info))=c.borrow().get_attr("alias").and_then(|a| participants.get(&a)){
+                xs.push(info.x);
+            }
+        });
+        if xs.is_empty(){
+            continue;
+        }
+        let x_min=xs.iter().cloned().fold(f32::INFINITY, f32::min);
+        let x_max=xs.iter().cloned().fold(f32::NEG_INFINITY, f32::max);
+
+        let margin=BOX_PADDING+(levels-level-1) as f32*BOX_NESTING_STEP;
+        let left=x_min-PARTICIPANT_BOX_WIDTH/2.0-margin;
+        let width=x_max-x_min+PARTICIPANT_BOX_WIDTH+2.0*margin;
+        let top=-((levels-level) as f32)*BOX_LABEL_HEIGHT;
+        let height=bottom+margin-top;
+        let color=elt.get_attr("color").unwrap_or_else(|| String::from(BOX_DEFAULT_COLOR));
+
+        let mut group=create_group(None);
+        set_source_attribute(&mut group, &elt.source);
+        group.push(Rc::new(RefCell::new(create_rect(left, top, width, height,
+            format!("fill:{};fill-opacity:0.5;stroke:#2e282a;stroke-width:0.3;stroke-opacity:1", color).as_str(),
+            Some(0.8), None))));
+        if let Some(QueryResult::Text(text))=elt.get("name/text()"){
+            let mut text_elt=create_text(left+width/2.0, top+BOX_LABEL_HEIGHT-2.5, TEXT_STYLE_BOLD, None);
+            text_elt.push_attribute("text-anchor","middle");
+            text_elt.push(Rc::new(RefCell::new(Element::new_str("tspan", &text))));
+            group.push(Rc::new(RefCell::new(text_elt)));
+        }
+        svg.borrow_mut().push(Rc::new(RefCell::new(group)));
+        draw_boxes(&child, participants, level+1, levels, bottom, svg);
+    }
+}
+
+// Width and height of the shape of each type of participant, with its name
+fn participant_size(participant_type:&str)->(f32, f32){
+    match participant_type{
+        "actor" => (PARTICIPANT_BOX_WIDTH, ACTOR_HEIGHT),
+        "boundary"|"control"|"entity" => (PARTICIPANT_BOX_WIDTH, ICON_HEIGHT),
+        "database" => (PARTICIPANT_BOX_WIDTH, DATABASE_HEIGHT),
+        "collections" => (PARTICIPANT_BOX_WIDTH, PARTICIPANT_BOX_HEIGHT+COLLECTIONS_OFFSET),
+        _ => (PARTICIPANT_BOX_WIDTH, PARTICIPANT_BOX_HEIGHT),
+    }
+}
+
+// Shape of the participant with its name, at the top of its lifeline and in the footbox.
+// Returns its height, the lifeline starts at the bottom of the shape
+fn draw_participant_shape(group:&mut Element, participant:&Element, x:f32, top:f32)->f32{
+    let participant_type=participant.get_tag();
+    let (width, height)=participant_size(&participant_type);
+    let mut shapes:Vec<Element>=vec![];
+    let line=|d:String| create_path(d.as_str(), ICON_LINE_STYLE, None);
+    // name in the shape, or below the icon
+    let text_y=match participant_type.as_str(){
+        "actor" => {
+            // stick figure
+            shapes.push(create_circle(x, top+2.5, 2.5, ICON_STYLE, None));
+            shapes.push(line(format!("m {},{} v 6 m -4,-4 h 8 m -4,4 l -3.5,5 m 3.5,-5 l 3.5,5", x, top+5.0)));
+            top+height-1.0
+        },
+        "boundary" => {
+            // circle, with a bar on its left
+            shapes.push(line(format!("m {},{} v {} m 0,{} h 3", x-ICON_RADIUS-1.0, top+1.0, 2.0*ICON_RADIUS, -ICON_RADIUS)));
+            shapes.push(create_circle(x+2.0, top+1.0+ICON_RADIUS, ICON_RADIUS, ICON_STYLE, None));
+            top+height-1.5
+        },
+        "control" => {
+            // circle, with an arrow on top
+            shapes.push(create_circle(x, top+1.0+ICON_RADIUS, ICON_RADIUS, ICON_STYLE, None));
+            shapes.push(line(format!("m {},{} l -2,1 2,1", x+1.0, top)));
+            top+height-1.5
+        },
+        "entity" => {
+            // underlined circle
+            shapes.push(create_circle(x, top+1.0+ICON_RADIUS, ICON_RADIUS, ICON_STYLE, None));
+            shapes.push(line(format!("m {},{} h {}", x-ICON_RADIUS, top+1.0+2.0*ICON_RADIUS, 2.0*ICON_RADIUS)));
+            top+height-1.5
+        },
+        "database" => {
+            // cylinder: body with the bottom half ellipse, then the top ellipse
+            let rx=width/2.0-2.0;
+            shapes.push(create_path(
+                format!("m {},{} v {} a {},{} 0 0 0 {},0 v {} a {},{} 0 0 0 {},0 a {},{} 0 0 0 {},0",
+                    x-rx, top+DATABASE_RY, height-2.0*DATABASE_RY, rx, DATABASE_RY, 2.0*rx, -(height-2.0*DATABASE_RY),
+                    rx, DATABASE_RY, -2.0*rx, rx, DATABASE_RY, 2.0*rx).as_str(),
+                ICON_STYLE, None));
+            top+height/2.0+2.5
+        },
+        "collections" => {
+            // a stack of boxes
+            for offset in &[COLLECTIONS_OFFSET, 0.0]{
+                shapes.push(create_rect(x-width/2.0+offset, top+COLLECTIONS_OFFSET-offset,
+                    width-COLLECTIONS_OFFSET, height-COLLECTIONS_OFFSET, PARTICIPANT_STYLE, Some(0.8), None));
+            }
+            top+COLLECTIONS_OFFSET+PARTICIPANT_BOX_HEIGHT/2.0-4.0
+        },
+        _ => {
+            shapes.push(create_rect(x-width/2.0, top, width, height, PARTICIPANT_STYLE, Some(0.8), None));
+            top+PARTICIPANT_BOX_HEIGHT/2.0-4.0
+        },
+    };
+    for shape in shapes{
+        group.push(Rc::new(RefCell::new(shape)));
+    }
+
+    if let Some(QueryResult::Text(text)) = participant.get("name/text()"){
+        let mut text_elt=create_text(x, text_y, TEXT_STYLE, None);
+        text_elt.push_attribute("text-anchor","middle");
+        text_elt.push(Rc::new(RefCell::new(Element::new_str("tspan", &text))));
+        group.push(Rc::new(RefCell::new(text_elt)));
+    }
+    return height;
+}
+
+// bar is the placeholder group made by ContentLayout::activate
+fn draw_activation_bar(bar:&Rcc<Element>, left:f32, y_start:f32, y_end:f32){
+    bar.borrow_mut().push(Rc::new(RefCell::new(create_rect(left, y_start, ACTIVATION_BOX_WIDTH, y_end-y_start, ACTIVATION_STYLE, None, None))));
+}
+
+fn children(e:&Rcc<Element>)->Vec<Rcc<Element>>{
+    match &e.borrow().content{
+        ElementContent::Tree(content) => content.children.clone(),
+        _ => vec![],
+    }
+}
+
+// The text of a note, line by line: pieces of text with their style.
+// Each paragraph is a line, and so is each line of code
+fn note_lines(note:&Rcc<Element>)->Vec<Vec<(String, String)>>{
+    let mut lines=vec![];
+    for child in children(note){
+        let elt=child.borrow();
+        if elt.is_tree() && elt.get_tag() == "code"{
+            if let Some(QueryResult::Text(code))=elt.get("text()"){
+                for line in code.lines(){
+                    lines.push(vec![(String::from(line), String::from("font-family:monospace;"))]);
+                }
+            }
+            continue;
+        }
+        let mut line=vec![];
+        collect_styled_text(&child, "", &mut line);
+        lines.push(line);
+    }
+    return lines;
+}
+
+fn collect_styled_text(e:&Rcc<Element>, style:&str, line:&mut Vec<(String, String)>){
+    let elt=e.borrow();
+    if elt.is_text(){
+        line.push((elt.get_text(), String::from(style)));
+        return;
+    }
+    let style=match elt.get_attr("format").unwrap_or_default().as_str(){
+        "bold" => format!("{}font-weight:bold;", style),
+        "italic" => format!("{}font-style:italic;", style),
+        "strikethrough" => format!("{}text-decoration:line-through;", style),
+        _ => String::from(style),
+    };
+    for child in children(e){
+        collect_styled_text(&child, &style, line);
+    }
+}
+
+fn note_size(lines:&[Vec<(String, String)>])->(f32, f32){
+    let max_chars=lines.iter()
+        .map(|line| line.iter().map(|(text, _)| text.chars().count()).sum::<usize>())
+        .max().unwrap_or(0);
+    let line_count=cmp::max(lines.len(), 1);
+    return (max_chars as f32*CHAR_AVERAGE_RATIO*FONT_SIZE+2.0*NOTE_PADDING+NOTE_FOLD,
+        line_count as f32*FONT_INTERLINE+2.0*NOTE_PADDING);
+}
+
+fn text_width(text:&str)->f32{
+    text.chars().count() as f32*CHAR_AVERAGE_RATIO_BOLD*FONT_SIZE
 }
 
 
@@ -372,6 +1297,41 @@ mod tests {
     use crate::datatypes::*;
     use std::rc::Rc;
 
+    // a participant drawn as tag (participant, actor, database...)
+    fn shape(tag: &str, alias: &str) -> Element {
+        Element::new(tag)
+            .attr("alias", alias)
+            .child(Element::new_str("name", alias))
+    }
+
+    fn participant(alias: &str) -> Element {
+        shape("participant", alias)
+    }
+
+    // header without footbox, with these participants
+    fn header(aliases: &[&str]) -> Element {
+        let mut header = Element::new("sequencediagram:header").attr("footbox", "hide");
+        for alias in aliases {
+            header = header.child(participant(alias));
+        }
+        return header;
+    }
+
+    #[test]
+    fn test_sequencebuilder_bad_input() {
+        let mut builder = SequenceDiagramBuilder::new();
+
+        let elements:Vec<Rcc<Element>>=vec![rcc(Element::new("sequencediagram:header"))];
+        assert!(builder.generate_svg(&elements).is_err());
+
+        let elements:Vec<Rcc<Element>>=vec![
+            rcc(Element::new("sequencediagram:content")),
+            rcc(Element::new("sequencediagram:header")),
+        ];
+        assert_eq!(builder.generate_svg(&elements).unwrap_err().kind,
+            ErrorKind::InvalidDiagram(String::from("Bad format for input data")));
+    }
+
     #[test]
     fn test_sequencebuilder1() {
 
@@ -426,4 +1386,487 @@ mod tests {
         }
     }
 
-}
\ No newline at end of file
+    #[test]
+    fn test_sequencebuilder_source() {
+        let source = SourceSpan {
+            file_name: Rc::new(String::from("doc \"1\".fgu")),
+            line: 3,
+            column: 5,
+        };
+        let elements:Vec<Rcc<Element>>=vec![
+            rcc(Element::new("sequencediagram:header")
+                .child(Element::new("participant")
+                    .attr("alias", "alice")
+                    .child(Element::new_str("name", "alice"))
+                    .src(&SourceSpan { line: 2, column: 1, ..source.clone() })
+                )
+            ),
+            rcc(Element::new("sequencediagram:content")
+                .child(Element::new("arrow")
+                    .attr("target", "alice")
+                    .attr("origin", "alice")
+                    .src(&source)
+                )
+                .child(Element::new("arrow")
+                    .attr("target", "alice")
+                    .attr("origin", "alice")
+                )
+            ),
+        ];
+
+        let svg = SequenceDiagramBuilder::new().generate_svg(&elements).unwrap();
+        assert!(svg.contains("<g data-src=\"doc &quot;1&quot;.fgu:2:1\">"));
+        assert!(svg.contains("<g data-src=\"doc &quot;1&quot;.fgu:3:5\">"));
+        assert_eq!(svg.matches("data-src").count(), 2);
+    }
+
+    #[test]
+    fn test_sequencebuilder_fragment() {
+        let arrow = || Element::new("arrow")
+            .attr("origin", "alice")
+            .attr("target", "bob")
+            .attr("line-style", "normal")
+            .attr("arrow-style", "normal");
+        let elements:Vec<Rcc<Element>>=vec![
+            rcc(Element::new("sequencediagram:header")
+                .child(Element::new("participant")
+                    .attr("alias", "alice")
+                    .child(Element::new_str("name", "alice"))
+                )
+                .child(Element::new("participant")
+                    .attr("alias", "bob")
+                    .child(Element::new_str("name", "bob"))
+                )
+            ),
+            rcc(Element::new("sequencediagram:content")
+                .child(Element::new("fragment")
+                    .attr("type", "alt")
+                    .child(Element::new("section")
+                        .child(Element::new_str("label", "success"))
+                        .child(arrow())
+                    )
+                    .child(Element::new("section")
+                        .child(Element::new_str("label", "failure"))
+                        .child(arrow())
+                    )
+                )
+            ),
+        ];
+
+        let svg = SequenceDiagramBuilder::new().generate_svg(&elements).unwrap();
+        assert!(svg.contains("alt\n"));
+        assert!(svg.contains("[success]\n"));
+        assert!(svg.contains("[failure]\n"));
+        assert_eq!(svg.matches("stroke-dasharray:2,1").count(), 1);
+    }
+
+    #[test]
+    fn test_sequencebuilder_activations() {
+        let arrow = |origin: &str, target: &str| Element::new("arrow")
+            .attr("origin", origin)
+            .attr("target", target)
+            .attr("line-style", "normal")
+            .attr("arrow-style", "normal");
+        let elements:Vec<Rcc<Element>>=vec![
+            rcc(Element::new("sequencediagram:header")
+                .child(Element::new("participant")
+                    .attr("alias", "alice")
+                    .child(Element::new_str("name", "alice"))
+                )
+                .child(Element::new("participant")
+                    .attr("alias", "bob")
+                    .child(Element::new_str("name", "bob"))
+                )
+            ),
+            rcc(Element::new("sequencediagram:content")
+                .child(arrow("alice", "bob"))
+                .child(Element::new("activate").attr("participant", "bob"))
+                .child(Element::new("activate").attr("participant", "bob"))
+                .child(arrow("bob", "alice"))
+                .child(Element::new("deactivate").attr("participant", "bob"))
+                // still active at the end
+            ),
+        ];
+
+        let svg = SequenceDiagramBuilder::new().generate_svg(&elements).unwrap();
+        assert_eq!(svg.matches(ACTIVATION_STYLE).count(), 2);
+        // bob is at x=80, the nested bar is shifted to the right
+        assert!(svg.contains("x=\"78.0000\""));
+        assert!(svg.contains("x=\"80.0000\""));
+        // and the message to alice starts from the left side of the bars
+        assert!(svg.contains("d=\"m 78,"));
+    }
+
+    #[test]
+    fn test_sequencebuilder_lifecycle() {
+        let elements:Vec<Rcc<Element>>=vec![
+            rcc(Element::new("sequencediagram:header")
+                .child(Element::new("participant")
+                    .attr("alias", "alice")
+                    .child(Element::new_str("name", "alice"))
+                )
+                .child(Element::new("participant")
+                    .attr("alias", "bob")
+                    .attr("created", "true")
+                    .child(Element::new_str("name", "bob"))
+                )
+            ),
+            rcc(Element::new("sequencediagram:content")
+                .child(Element::new("create").attr("participant", "bob"))
+                .child(Element::new("arrow")
+                    .attr("origin", "alice")
+                    .attr("target", "bob")
+                )
+                .child(Element::new("destroy").attr("participant", "bob"))
+            ),
+        ];
+
+        let svg = SequenceDiagramBuilder::new().generate_svg(&elements).unwrap();
+        // the message points to the middle of the box of bob, which is below the header
+        assert!(svg.contains("d=\"m 40,29 h 26\""));
+        assert!(svg.contains("<rect x=\"66.0000\" y=\"20.0000\""));
+        // and the lifeline of bob stops at the cross
+        assert!(svg.contains("d=\"m 80,38 v 4\""));
+        assert!(svg.contains("d=\"m 77,39 l 6,6 m 0,-6 l -6,6\""));
+    }
+
+    #[test]
+    fn test_sequencebuilder_notes() {
+        let text = |text: &str| Element::new("format")
+            .attr("format", "paragraph")
+            .child(Element::str(text));
+        let elements:Vec<Rcc<Element>>=vec![
+            rcc(Element::new("sequencediagram:header")
+                .child(Element::new("participant")
+                    .attr("alias", "alice")
+                    .child(Element::new_str("name", "alice"))
+                )
+                .child(Element::new("participant")
+                    .attr("alias", "bob")
+                    .child(Element::new_str("name", "bob"))
+                )
+            ),
+            rcc(Element::new("sequencediagram:content")
+                // 30 characters, wider than the default margin
+                .child(Element::new("note")
+                    .attr("shape", "note")
+                    .attr("position", "left")
+                    .attr("participant", "alice")
+                    .child(text("a note on the left of alice..."))
+                )
+                .child(Element::new("note")
+                    .attr("shape", "hnote")
+                    .attr("position", "over")
+                    .attr("participant", "alice")
+                    .attr("to", "bob")
+                    .child(text("over"))
+                    .child(text("both"))
+                )
+                .child(Element::new("note")
+                    .attr("shape", "rnote")
+                    .attr("position", "right")
+                    .attr("participant", "bob")
+                    .child(text("r"))
+                )
+            ),
+        ];
+
+        let svg = SequenceDiagramBuilder::new().generate_svg(&elements).unwrap();
+        assert_eq!(svg.matches(NOTE_STYLE).count(), 3);
+        // alice moved to the right to make room for the note: 72+2*2+3 wide, plus 2*3 of margin
+        assert!(svg.contains("d=\"m 85,18 v "));
+        // the folded note ends before the lifeline of alice
+        assert!(svg.contains("d=\"m 3,21 h 76 l 3,3 v "));
+        // over the two lifelines, with two lines of text
+        assert!(svg.contains("d=\"m 77,42 l 2,-8 h 52 l 2,8 l -2,8 h -52 z\""));
+        assert!(svg.contains("both\n"));
+        // on the right of bob
+        assert!(svg.contains("<rect x=\"128.0000\" y=\"53.0000\""));
+    }
+
+    #[test]
+    fn test_sequencebuilder_vertical_layout() {
+        let elements:Vec<Rcc<Element>>=vec![
+            rcc(Element::new("sequencediagram:header")
+                .child(Element::new("participant")
+                    .attr("alias", "alice")
+                    .child(Element::new_str("name", "alice"))
+                )
+                .child(Element::new("participant")
+                    .attr("alias", "bob")
+                    .child(Element::new_str("name", "bob"))
+                )
+            ),
+            rcc(Element::new("sequencediagram:content")
+                .child(Element::new("delay").child(Element::new_str("text", "later")))
+                .child(Element::new("space"))
+                .child(Element::new("space").attr("height", "45"))
+                .child(Element::new("separator").child(Element::new_str("text", "part 2")))
+            ),
+        ];
+
+        let svg = SequenceDiagramBuilder::new().generate_svg(&elements).unwrap();
+        // the delay is 12+6 high, on both lifelines
+        assert_eq!(svg.matches(DELAY_STYLE).count(), 2);
+        assert!(svg.contains("d=\"m 40,18 v 18\""));
+        assert!(svg.contains("d=\"m 80,18 v 18\""));
+        assert!(svg.contains("later\n"));
+        // then 12 and 45 of space, and the separator across the whole width
+        assert!(svg.contains("d=\"m 0,98.25 h 160 m 0,1.5 h -160\""));
+        assert!(svg.contains("part 2\n"));
+        // lifelines go until the end: 18+12+45+12, plus the margins
+        assert!(svg.contains("d=\"m 40,18 v 95\""));
+    }
+
+    #[test]
+    fn test_sequencebuilder_header_options() {
+        let header = |footbox: &str| Element::new("sequencediagram:header")
+            .attr("footbox", footbox)
+            .attr("unlinked", "hide")
+            .child(Element::new_str("title", "My title"))
+            .child(Element::new("participant")
+                .attr("alias", "alice")
+                .child(Element::new_str("name", "alice"))
+            )
+            .child(Element::new("participant")
+                .attr("alias", "lonely")
+                .child(Element::new_str("name", "lonely"))
+            )
+            .child(Element::new("participant")
+                .attr("alias", "bob")
+                .child(Element::new_str("name", "bob"))
+            );
+        let content = || Element::new("sequencediagram:content")
+            .child(Element::new("arrow")
+                .attr("origin", "alice")
+                .attr("target", "bob")
+            );
+
+        let svg = SequenceDiagramBuilder::new().generate_svg(&[rcc(header("show")), rcc(content())]).unwrap();
+        // the title is above the diagram, that is moved down
+        assert!(svg.contains("<text xml:space=\"preserve\" style=\"font-style:normal;font-weight:bold;font-size:5px;"));
+        assert!(svg.contains("My title\n"));
+        assert!(svg.contains("<g transform=\"translate(0.0000, 12.0000)\">"));
+        // lonely is not drawn, bob is next to alice
+        assert!(!svg.contains("lonely"));
+        assert!(svg.contains("d=\"m 80,18 v "));
+        // two boxes by participant, the lifeline ends at the footbox
+        assert_eq!(svg.matches(PARTICIPANT_STYLE).count(), 4);
+        assert!(svg.contains("d=\"m 40,18 v 14\""));
+        assert!(svg.contains("<rect x=\"26.0000\" y=\"32.0000\""));
+        assert!(svg.contains("viewBox=\"0 0 160.0000 64.0000\""));
+
+        let svg = SequenceDiagramBuilder::new().generate_svg(&[rcc(header("hide")), rcc(content())]).unwrap();
+        assert_eq!(svg.matches(PARTICIPANT_STYLE).count(), 2);
+        assert!(svg.contains("viewBox=\"0 0 160.0000 44.0000\""));
+    }
+
+    #[test]
+    fn test_sequencebuilder_boxes() {
+        let elements:Vec<Rcc<Element>>=vec![
+            rcc(Element::new("sequencediagram:header")
+                .attr("footbox", "hide")
+                .child(Element::new("box")
+                    .child(Element::new_str("name", "outer"))
+                    .child(participant("alice"))
+                    .child(Element::new("box")
+                        .attr("color", "#c0ffee")
+                        .child(Element::new_str("name", "inner"))
+                        .child(participant("bob"))
+                    )
+                )
+                .child(participant("carol"))
+                .child(Element::new("box")
+                    .child(Element::new_str("name", "empty"))
+                )
+            ),
+            rcc(Element::new("sequencediagram:content")),
+        ];
+
+        let svg = SequenceDiagramBuilder::new().generate_svg(&elements).unwrap();
+        // two levels of labels above the participants
+        assert!(svg.contains("<g transform=\"translate(0.0000, 16.0000)\">"));
+        // the outer box goes around alice and bob, and a bit further than the inner one
+        assert!(svg.contains("<rect x=\"19.0000\" y=\"-16.0000\" width=\"82.0000\" height=\"49.0000\" style=\"fill:#f4f1ea;"));
+        assert!(svg.contains("<rect x=\"62.0000\" y=\"-8.0000\" width=\"36.0000\" height=\"38.0000\" style=\"fill:#c0ffee;"));
+        assert!(svg.contains("outer\n"));
+        assert!(svg.contains("inner\n"));
+        assert!(!svg.contains("empty"));
+        assert!(svg.contains("viewBox=\"0 0 200.0000 49.0000\""));
+    }
+
+    #[test]
+    fn test_sequencebuilder_participant_shapes() {
+        let mut shapes=header(&[]);
+        for tag in &["participant", "actor", "boundary", "control", "entity", "database", "collections"]{
+            shapes=shapes.child(shape(tag, tag));
+        }
+        let elements:Vec<Rcc<Element>>=vec![
+            rcc(shapes),
+            rcc(Element::new("sequencediagram:content")
+                .child(Element::new("arrow")
+                    .attr("origin", "actor")
+                    .attr("target", "database")
+                )
+            ),
+        ];
+
+        let svg = SequenceDiagramBuilder::new().generate_svg(&elements).unwrap();
+        // the stick figure and the icons are circles
+        assert_eq!(svg.matches("<circle").count(), 4);
+        // collections are two boxes
+        assert_eq!(svg.matches("<rect").count(), 3);
+        // every lifeline starts at the bottom of its shape
+        assert!(svg.contains("d=\"m 40,18 v"));
+        assert!(svg.contains("d=\"m 80,22 v"));
+        assert!(svg.contains("d=\"m 120,19 v"));
+        assert!(svg.contains("d=\"m 240,21 v"));
+        assert!(svg.contains("d=\"m 280,20 v"));
+        // the message goes from the actor lifeline to the database one
+        assert!(svg.contains("d=\"m 80,28 h 160\""));
+        assert!(svg.contains("d=\"m 236,27 4,1 -4,1 z\""));
+    }
+
+    #[test]
+    fn test_sequencebuilder_arrow_styles() {
+        let arrow = |line: &str, head: &str| Element::new("arrow")
+            .attr("origin", "alice")
+            .attr("target", "bob")
+            .attr("line-style", line)
+            .attr("arrow-style", head);
+        let elements:Vec<Rcc<Element>>=vec![
+            rcc(header(&["alice", "bob"])),
+            rcc(Element::new("sequencediagram:content")
+                .child(arrow("dotted", "fine"))
+                .child(arrow("normal", "top"))
+                .child(arrow("normal", "bidirectional"))
+                .child(arrow("normal", "normal").attr("decor", "cross"))
+                .child(arrow("normal", "normal").attr("decor", "round"))
+            ),
+        ];
+
+        let svg = SequenceDiagramBuilder::new().generate_svg(&elements).unwrap();
+        assert_eq!(svg.matches("stroke-dasharray:1.2,0.8").count(), 1);
+        // open head
+        assert!(svg.contains("d=\"m 76,23 l 4,1 -4,1\""));
+        // upper half only
+        assert!(svg.contains("d=\"m 76,29 l 4,1 h -4 z\""));
+        // both ends
+        assert!(svg.contains("d=\"m 76,35 4,1 -4,1 z\""));
+        assert!(svg.contains("d=\"m 44,35 -4,1 4,1 z\""));
+        // the cross replaces the head, the circle is after it
+        assert!(svg.contains("d=\"m 77,40.5 l 3,3 m 0,-3 l -3,3\""));
+        assert!(svg.contains("<circle cx=\"78.5000\" cy=\"48.0000\""));
+        assert!(svg.contains("d=\"m 73,47 4,1 -4,1 z\""));
+    }
+
+    #[test]
+    fn test_sequencebuilder_self_message() {
+        let elements:Vec<Rcc<Element>>=vec![
+            rcc(header(&["alice", "bob"])),
+            rcc(Element::new("sequencediagram:content")
+                .child(Element::new("arrow")
+                    .attr("origin", "alice")
+                    .attr("target", "alice")
+                    .attr("line-style", "normal")
+                    .attr("arrow-style", "normal")
+                    .child(Element::new_str("text", "retry with a longer timeout"))
+                )
+            ),
+        ];
+
+        let svg = SequenceDiagramBuilder::new().generate_svg(&elements).unwrap();
+        // out on the top, back with the head on the bottom
+        assert!(svg.contains("d=\"m 40,22 h 10 v 6\""));
+        assert!(svg.contains("d=\"m 50,28 h -10\""));
+        assert!(svg.contains("d=\"m 44,27 -4,1 4,1 z\""));
+        assert!(svg.contains("<text xml:space=\"preserve\" style=\"font-style:normal;font-weight:normal;font-size:3.8px;line-height:1.25;font-family:sans-serif;letter-spacing:0px;word-spacing:0px;fill:#000000;fill-opacity:1;\" x=\"52.0000\" y=\"24.0000\">"));
+        // bob is pushed away by the label, that starts at 52
+        assert!(svg.contains("d=\"m 122.8,18 v 20\""));
+        assert!(svg.contains("viewBox=\"0 0 202.8000 38.0000\""));
+    }
+
+    #[test]
+    fn test_sequencebuilder_border_messages() {
+        let elements:Vec<Rcc<Element>>=vec![
+            rcc(header(&["api"])),
+            rcc(Element::new("sequencediagram:content")
+                .child(Element::new("arrow")
+                    .attr("target", "api")
+                    .attr("line-style", "normal")
+                    .attr("arrow-style", "normal")
+                    .attr("border", "left")
+                    .child(Element::new_str("text", "GET /items/{item_id}"))
+                )
+                .child(Element::new("arrow")
+                    .attr("origin", "api")
+                    .attr("line-style", "normal")
+                    .attr("arrow-style", "normal")
+                    .attr("border", "right")
+                    .child(Element::new_str("text", "publish the audit event"))
+                )
+            ),
+        ];
+
+        let svg = SequenceDiagramBuilder::new().generate_svg(&elements).unwrap();
+        // the left margin fits the label, the border is 2 from the edge
+        assert!(svg.contains("d=\"m 2,26 h 56\""));
+        // the label goes to the right of the last participant
+        assert!(svg.contains("d=\"m 58,34 h 97.2\""));
+        assert!(svg.contains("viewBox=\"0 0 157.2000 "));
+    }
+
+    #[test]
+    fn test_sequencebuilder_autonumber() {
+        let arrow = |number: &str| Element::new("arrow")
+            .attr("origin", "alice")
+            .attr("target", "bob")
+            .attr("line-style", "normal")
+            .attr("arrow-style", "normal")
+            .attr("number", number)
+            .attr("number-style", "bold");
+        let elements:Vec<Rcc<Element>>=vec![
+            rcc(header(&["alice", "bob"])),
+            rcc(Element::new("sequencediagram:content")
+                .child(arrow("[0001]").child(Element::new_str("text", "a rather long message")))
+                .child(arrow("[0002]"))
+            ),
+        ];
+
+        let svg = SequenceDiagramBuilder::new().generate_svg(&elements).unwrap();
+        assert!(svg.contains("<tspan style=\"font-weight:bold\">\n\t\t\t\t\t[0001] \n"));
+        assert!(svg.contains("<tspan style=\"font-weight:bold\">\n\t\t\t\t\t[0002]\n"));
+        // the number makes the label wider
+        assert!(svg.contains("d=\"m 113.16,18 v"));
+    }
+
+    #[test]
+    fn test_sequencebuilder_constraints() {
+        let arrow = |origin: &str, target: &str, id: &str| Element::new("arrow")
+            .attr("origin", origin)
+            .attr("target", target)
+            .attr("line-style", "normal")
+            .attr("arrow-style", "normal")
+            .attr("id", id);
+        let elements:Vec<Rcc<Element>>=vec![
+            rcc(header(&["alice", "bob"])),
+            rcc(Element::new("sequencediagram:content")
+                .child(arrow("alice", "bob", "req"))
+                .child(arrow("bob", "alice", "resp"))
+                .child(Element::new("constraint")
+                    .attr("start", "req")
+                    .attr("end", "resp")
+                    .child(Element::new_str("text", "at most 200 ms, or the client gives up"))
+                )
+            ),
+        ];
+
+        let svg = SequenceDiagramBuilder::new().generate_svg(&elements).unwrap();
+        // the left margin fits the text
+        assert!(svg.contains("d=\"m 98.2"));
+        // the line goes from one message to the other
+        assert!(svg.contains(",24 h 3 m -1.5,0 v 6 m -1.5,0 h 3 "));
+        assert!(svg.contains("text-anchor=\"end\""));
+        assert!(svg.contains("at most 200 ms, or the client gives up\n"));
+    }
+}
@@ -1,4 +1,4 @@
-use crate::datatypes::Element;
+use crate::datatypes::{Element, SourceSpan};
 
 pub fn create_svg(width:f32, height:f32)->Element{
     // <svg version="1.1" xmlns="http://www.w3.org/2000/svg" 
@@ -39,6 +39,20 @@ pub fn create_rect(x:f32, y:f32, width:f32, height:f32, style:&str, r:Option<f32
 }
 
 
+pub fn create_circle(cx:f32, cy:f32, r:f32, style:&str, id:Option<&str>)->Element{
+    // <circle cx="40" cy="3" r="2.5" style="fill:#ede7d9;stroke:#2e282a" id="circle12" />
+    let mut elt: Element = Element::new("circle")
+        .attr("cx", &format!("{:.4}", cx))
+        .attr("cy", &format!("{:.4}", cy))
+        .attr("r", &format!("{:.4}", r))
+        .attr("style", style);
+    if let Some(id)=id{
+        elt.push_attribute("id", id);
+    }
+    return elt;
+}
+
+
 pub fn create_translate_group(x:f32, y:f32, id:Option<&str>) ->Element{
     //<g transform="translate(0,-97)" id="layer1">
     let mut elt: Element = Element::new("g")
@@ -98,7 +112,7 @@ pub fn create_tspan(x:Option<f32>, y:Option<f32>, style:Option<&str>, id:Option<
     // x="31.714447"
     // y="105.522"
     // style="font-size:3.88055556px;stroke-width:0.26458332;">
-    let mut elt: Element = Element::new("text");
+    let mut elt: Element = Element::new("tspan");
     if let Some(style) = style{
         elt.push_attribute("style", style);
     }
@@ -114,3 +128,39 @@ pub fn create_tspan(x:Option<f32>, y:Option<f32>, style:Option<&str>, id:Option<
     return elt;
 }
 
+
+// to_xml doesn't escape anything, file names can contain any character
+pub fn escape_attribute(value:&str)->String{
+    value.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+// data-src="file.fgu:12:7", so that a viewer can go back from an svg element to its source line
+pub fn set_source_attribute(elt:&mut Element, source:&Option<SourceSpan>){
+    if let Some(source)=source{
+        elt.push_attribute("data-src", &escape_attribute(&source.to_string()));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::datatypes::rcc;
+
+    #[test]
+    fn test_create_tspan() {
+        // a tspan is a span of text inside a <text>, not an other <text>
+        let tspan = create_tspan(Some(1.5), None, Some("font-weight:bold"), None);
+        assert_eq!(tspan.get_tag(), "tspan");
+        assert_eq!(tspan.get_attributes(), &vec![
+            (String::from("style"), String::from("font-weight:bold")),
+            (String::from("x"), String::from("1.5000")),
+        ]);
+
+        let mut text = create_text(0.0, 0.0, "", None);
+        text.push(rcc(create_tspan(None, None, None, None)));
+        assert!(text.to_xml().contains("<tspan"));
+    }
+}
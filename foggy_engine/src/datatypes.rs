@@ -1,7 +1,9 @@
+use std::fmt;
 use std::rc::Rc;
 use std::cell::{RefCell};
 // to parse xpath-like expressions
 use crate::parseutils::*;
+use crate::errors::{ErrorKind, FoggyError};
 
 
 #[derive(Debug)]
@@ -9,7 +11,11 @@ pub struct LineWithContext {
     pub text: String,
     pub line: u32,
     pub file_name: Rc<String>,
+    // participants declared in an imported file live in the namespace of that file,
+    // eg: lib for '/import lib.fgu'. Empty for the top level file, dotted for nested imports
     pub namespace: Rc<String>,
+    // set when the text is not the source as written, eg: macro expansion
+    pub note: Option<Rc<String>>,
 }
 
 #[derive(Debug)]
@@ -18,6 +24,8 @@ pub struct SliceWithContext<'a> {
     pub line: u32,
     pub pos: u32,
     pub file_name: Rc<String>,
+    // namespace of the line, "" for the top level file, see LineWithContext
+    pub namespace: Rc<String>,
 }
 
 
@@ -29,9 +37,40 @@ impl<'a> SliceWithContext<'a>{
             line: 0,
             pos: 0,
             file_name: Rc::new(String::from("file.txt")),
+            namespace: Rc::new(String::new()),
+        }
+    }
+
+    // Location in this slice.
+    // rest is the end of the slice, starting at the location
+    pub fn span_at(&self, rest: &str) -> SourceSpan {
+        let offset = self.slice.len().saturating_sub(rest.len()) as u32;
+        SourceSpan {
+            file_name: Rc::clone(&self.file_name),
+            line: self.line,
+            column: self.pos + offset + 1,
         }
     }
 
+    // The end of this slice, starting at rest, to be parsed on its own
+    pub fn tail(&self, rest: &'a str) -> SliceWithContext<'a> {
+        let offset = self.slice.len().saturating_sub(rest.len()) as u32;
+        SliceWithContext {
+            slice: rest,
+            line: self.line,
+            pos: self.pos + offset,
+            file_name: Rc::clone(&self.file_name),
+            namespace: Rc::clone(&self.namespace),
+        }
+    }
+
+    // Build an error located in this slice.
+    // rest is the end of the slice, starting where the error was found
+    pub fn error_at(&self, rest: &str, kind: ErrorKind) -> FoggyError {
+        let span = self.span_at(rest);
+        FoggyError::at(kind, &span.file_name, span.line, span.column)
+    }
+
 }
 
 pub type Rcc<T> = Rc<RefCell<T>>;
@@ -57,10 +96,34 @@ pub enum ElementContent {
     // binary type ??
 }
 
+// Where an element comes from in the source, line and column start at 1
+#[derive(Debug, Clone, PartialEq)]
+pub struct SourceSpan {
+    pub file_name: Rc<String>,
+    pub line: u32,
+    pub column: u32,
+}
+
+// file.fgu:12:7
+impl fmt::Display for SourceSpan {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}:{}:{}", self.file_name, self.line, self.column)
+    }
+}
+
 #[derive(Debug)]
-#[derive(PartialEq)]
 pub struct Element {
     pub content: ElementContent,
+    // set by the parsers, None for elements that don't come from a line of source
+    pub source: Option<SourceSpan>,
+}
+
+// The source doesn't change what the element is,
+// elements built by hand in tests are equal to the parsed ones
+impl PartialEq for Element {
+    fn eq(&self, other: &Element) -> bool {
+        self.content == other.content
+    }
 }
 
 
@@ -81,7 +144,7 @@ impl Element {
                 children: vec![],
                 attributes:vec![],
             }),
-            
+            source: None,
         }
     }
 
@@ -89,6 +152,7 @@ impl Element {
     pub fn str(text: &str) -> Element{
         Element {
             content: ElementContent::Text(String::from(text)),
+            source: None,
         }
     }
 
@@ -109,6 +173,12 @@ impl Element {
         return self;
     }
 
+    // builder to set the source
+    pub fn src(mut self, source: &SourceSpan) -> Self{
+        self.source = Some(source.clone());
+        return self;
+    }
+
     //Deprecated
     pub fn new_str(tag: &str, text: &str) -> Element{
         Element::new(tag).child(Element::str(text))
@@ -235,12 +305,12 @@ impl Element {
     // relative path to children
     // text()
     pub fn get(&self, path:&str)->Option<QueryResult>{
-        println!("{:?} {:?}\n\n", self, path);
+        //println!("{:?} {:?}\n\n", self, path);
 
         let (remaining, prefix)=consume_until_token_in_list(path, &["/"]).unwrap();
 
 
-        println!("prefix {:?}  remaining {:?}\n", prefix, remaining);
+        //println!("prefix {:?}  remaining {:?}\n", prefix, remaining);
 
         match &self.content{
             ElementContent::Tree(content) =>{
@@ -292,7 +362,7 @@ impl Element {
 
 #[derive(Debug)]
 pub struct Document {
-    pub children: Vec<Element>,
+    pub children: Vec<Rcc<Element>>,
 }
 
 // We use this structure so that we don't have to pass the closure
@@ -0,0 +1,163 @@
+use std::error::Error;
+use std::fmt;
+use std::rc::Rc;
+
+// What went wrong
+#[derive(Debug, Clone, PartialEq)]
+pub enum ErrorKind {
+    Io(String),             // file can't be read, the message comes from the datasource
+    AccessDenied(String),   // file rejected by the datasource, the message says why
+    InvalidStatement,       // malformed preprocessor statement
+    CircularInclude(Vec<String>), // the files of the include chain, the last one is already in the chain
+    IncludeTooDeep(usize),  // maximum include depth
+    UnfinishedString,
+    UnexpectedToken(String),
+    Syntax(String),         // any other parsing error
+    UnclosedBlock,
+    UnexpectedEndOfInput,
+    NotImplemented(String),
+    InvalidDiagram(String), // builder input doesn't have the expected structure
+    Internal(String),       // runtime error, this is a bug
+}
+
+impl fmt::Display for ErrorKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ErrorKind::Io(s) => write!(f, "{}", s),
+            ErrorKind::AccessDenied(s) => write!(f, "{}", s),
+            ErrorKind::InvalidStatement => write!(f, "invalid statement"),
+            ErrorKind::CircularInclude(chain) => write!(f, "circular include: {}", chain.join(" → ")),
+            ErrorKind::IncludeTooDeep(max) => write!(f, "too many nested includes (maximum is {})", max),
+            ErrorKind::UnfinishedString => write!(f, "unfinished string"),
+            ErrorKind::UnexpectedToken(s) => write!(f, "unexpected token '{}'", s),
+            ErrorKind::Syntax(s) => write!(f, "{}", s),
+            ErrorKind::UnclosedBlock => write!(f, "block is not closed"),
+            ErrorKind::UnexpectedEndOfInput => write!(f, "unexpected end of input"),
+            ErrorKind::NotImplemented(s) => write!(f, "{} is not implemented", s),
+            ErrorKind::InvalidDiagram(s) => write!(f, "{}", s),
+            ErrorKind::Internal(s) => write!(f, "internal error: {}", s),
+        }
+    }
+}
+
+// Error type for the whole engine: preprocessor, datasources, parsers and builders.
+// Location is optional, some errors don't come from a source line.
+// line and column start at 1, 0 means unknown.
+#[derive(Debug, Clone, PartialEq)]
+pub struct FoggyError {
+    pub file_name: Option<Rc<String>>,
+    pub line: u32,
+    pub column: u32,
+    pub kind: ErrorKind,
+    // more context, eg: the macro a line was expanded from
+    pub note: Option<String>,
+}
+
+impl FoggyError {
+    // Error without location
+    pub fn new(kind: ErrorKind) -> FoggyError {
+        FoggyError {
+            file_name: None,
+            line: 0,
+            column: 0,
+            kind,
+            note: None,
+        }
+    }
+
+    pub fn at(kind: ErrorKind, file_name: &Rc<String>, line: u32, column: u32) -> FoggyError {
+        FoggyError {
+            file_name: Some(Rc::clone(file_name)),
+            line,
+            column,
+            kind,
+            note: None,
+        }
+    }
+
+    // Set the location, only if the error doesn't have one yet.
+    // Used when an error goes up through a layer that knows where we are,
+    // for instance a datasource error gets the location of the /include statement
+    pub fn with_location(mut self, file_name: &Rc<String>, line: u32, column: u32) -> FoggyError {
+        if self.file_name == None {
+            self.file_name = Some(Rc::clone(file_name));
+            self.line = line;
+            self.column = column;
+        }
+        return self;
+    }
+
+    // Same as with_location, the first note is the most precise one
+    pub fn with_note(mut self, note: &str) -> FoggyError {
+        if self.note == None {
+            self.note = Some(String::from(note));
+        }
+        return self;
+    }
+}
+
+// file.fgu:12:7: unfinished string
+// file.fgu:12: unfinished string (expanded from macro 'call' defined at lib.fgu:3)
+impl fmt::Display for FoggyError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if let Some(file_name) = &self.file_name {
+            write!(f, "{}:", file_name)?;
+            if self.line > 0 {
+                write!(f, "{}:", self.line)?;
+                if self.column > 0 {
+                    write!(f, "{}:", self.column)?;
+                }
+            }
+            write!(f, " ")?;
+        }
+        write!(f, "{}", self.kind)?;
+        if let Some(note) = &self.note {
+            write!(f, " ({})", note)?;
+        }
+        Ok(())
+    }
+}
+
+impl Error for FoggyError {}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_display() {
+        let file_name = Rc::new(String::from("file.fgu"));
+        assert_eq!(
+            FoggyError::at(ErrorKind::UnfinishedString, &file_name, 12, 7).to_string(),
+            "file.fgu:12:7: unfinished string"
+        );
+        assert_eq!(
+            FoggyError::at(ErrorKind::UnclosedBlock, &file_name, 3, 0).to_string(),
+            "file.fgu:3: block is not closed"
+        );
+        assert_eq!(
+            FoggyError::at(ErrorKind::InvalidStatement, &file_name, 0, 0).to_string(),
+            "file.fgu: invalid statement"
+        );
+        assert_eq!(
+            FoggyError::new(ErrorKind::Io(String::from("cannot open 'a.fgu'"))).to_string(),
+            "cannot open 'a.fgu'"
+        );
+    }
+
+    #[test]
+    fn test_with_location() {
+        let file_name = Rc::new(String::from("file.fgu"));
+        let other = Rc::new(String::from("other.fgu"));
+
+        let error = FoggyError::new(ErrorKind::UnexpectedEndOfInput).with_location(&file_name, 2, 1);
+        assert_eq!(error.to_string(), "file.fgu:2:1: unexpected end of input");
+        // location already known, keep it
+        let error = error.with_location(&other, 5, 5);
+        assert_eq!(error.to_string(), "file.fgu:2:1: unexpected end of input");
+
+        let error = error.with_note("first").with_note("second");
+        assert_eq!(error.to_string(), "file.fgu:2:1: unexpected end of input (first)");
+    }
+}
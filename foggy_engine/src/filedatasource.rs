@@ -0,0 +1,205 @@
+use std::fs::File;
+use std::io::{BufRead, BufReader};
+use std::path::{Component, Path, PathBuf};
+
+use crate::preprocessor::Datasource;
+use crate::errors::{ErrorKind, FoggyError};
+
+// Datasource that reads files from the filesystem, sandboxed in a root directory.
+//
+// File names are handled as paths relative to the root:
+// - top level files are relative to the root itself
+// - imported/included files are relative to the directory of the file that imports them
+// Any path that would leave the root is rejected: absolute paths, '..' going above the root,
+// and symlinks pointing outside of the root tree.
+// This is what we want when rendering documents we don't trust.
+pub struct FileDatasource {
+    root: PathBuf,
+}
+
+impl FileDatasource {
+    pub fn new(root: &Path) -> Result<FileDatasource, FoggyError> {
+        // canonical root, so that the symlink check in get_data compares real paths
+        match root.canonicalize() {
+            Ok(r) => Ok(FileDatasource { root: r }),
+            Err(e) => Err(FoggyError::new(ErrorKind::Io(format!("invalid root directory '{}': {}", root.display(), e)))),
+        }
+    }
+
+    pub fn get_root(&self) -> &Path {
+        &self.root
+    }
+}
+
+// Lexical normalization of a relative path: remove '.' and apply '..'
+// Returns None if the path is absolute or if it goes above its starting point
+fn normalize_relative(path: &Path) -> Option<PathBuf> {
+    let mut normalized = PathBuf::new();
+    for component in path.components() {
+        match component {
+            Component::CurDir => (),
+            Component::ParentDir => {
+                if !normalized.pop() {
+                    return None;
+                }
+            }
+            Component::Normal(c) => normalized.push(c),
+            Component::RootDir | Component::Prefix(_) => return None,
+        }
+    }
+    return Some(normalized);
+}
+
+fn access_denied(filename: &str, reason: &str) -> FoggyError {
+    FoggyError::new(ErrorKind::AccessDenied(format!("access to '{}' blocked: {}", filename, reason)))
+}
+
+fn cannot_open(filename: &str, e: std::io::Error) -> FoggyError {
+    FoggyError::new(ErrorKind::Io(format!("cannot open '{}': {}", filename, e)))
+}
+
+impl Datasource for FileDatasource {
+    fn resolve(&self, filename: &str, parent: Option<&str>) -> Result<String, FoggyError> {
+        if Path::new(filename).is_absolute() {
+            return Err(access_denied(filename, "absolute paths are not allowed"));
+        }
+
+        let mut path = PathBuf::new();
+        if let Some(p) = parent {
+            if let Some(dir) = Path::new(p).parent() {
+                path.push(dir);
+            }
+        }
+        path.push(filename);
+
+        match normalize_relative(&path) {
+            Some(p) => Ok(p.to_string_lossy().into_owned()),
+            None => Err(access_denied(filename, "path is outside of the root directory")),
+        }
+    }
+
+    fn get_data(&self, filename: &str) -> Result<Box<dyn BufRead>, FoggyError> {
+        let path = match normalize_relative(Path::new(filename)) {
+            Some(p) => self.root.join(p),
+            None => return Err(access_denied(filename, "path is outside of the root directory")),
+        };
+
+        // Follow symlinks, the real file shall still be in the root tree
+        let real_path = match path.canonicalize() {
+            Ok(p) => p,
+            Err(e) => return Err(cannot_open(filename, e)),
+        };
+        if !real_path.starts_with(&self.root) {
+            return Err(access_denied(filename, "file is outside of the root directory"));
+        }
+
+        match File::open(&real_path) {
+            Ok(f) => Ok(Box::new(BufReader::new(f))),
+            Err(e) => Err(cannot_open(filename, e)),
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::datatypes::LineWithContext;
+    use crate::preprocessor::Preprocessor;
+    use std::fs;
+    use std::io::Read;
+
+    // Creates a fresh directory tree for a test:
+    // <tmp>/<name>/root/main.fgu
+    // <tmp>/<name>/root/sub/lib.fgu
+    // <tmp>/<name>/secret.fgu
+    fn create_tree(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("foggy_filedatasource_{}_{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(dir.join("root/sub")).unwrap();
+        fs::write(dir.join("root/main.fgu"), "main").unwrap();
+        fs::write(dir.join("root/sub/lib.fgu"), "lib").unwrap();
+        fs::write(dir.join("secret.fgu"), "secret").unwrap();
+        return dir;
+    }
+
+    fn read_all(mut data: Box<dyn BufRead>) -> String {
+        let mut s = String::new();
+        data.read_to_string(&mut s).unwrap();
+        return s;
+    }
+
+    #[test]
+    fn test_resolve() {
+        let dir = create_tree("resolve");
+        let source = FileDatasource::new(&dir.join("root")).unwrap();
+
+        let resolve = |f, p| source.resolve(f, p).map_err(|e| e.to_string());
+
+        assert_eq!(resolve("main.fgu", None), Ok(String::from("main.fgu")));
+        assert_eq!(resolve("./main.fgu", None), Ok(String::from("main.fgu")));
+        assert_eq!(resolve("lib.fgu", Some("sub/other.fgu")), Ok(String::from("sub/lib.fgu")));
+        assert_eq!(resolve("../main.fgu", Some("sub/lib.fgu")), Ok(String::from("main.fgu")));
+        assert_eq!(resolve("sub/../main.fgu", Some("main.fgu")), Ok(String::from("main.fgu")));
+        assert_eq!(
+            resolve("../secret.fgu", Some("main.fgu")),
+            Err(String::from("access to '../secret.fgu' blocked: path is outside of the root directory"))
+        );
+        assert_eq!(
+            resolve("../../secret.fgu", Some("sub/lib.fgu")),
+            Err(String::from("access to '../../secret.fgu' blocked: path is outside of the root directory"))
+        );
+        assert_eq!(
+            resolve("/etc/passwd", Some("main.fgu")),
+            Err(String::from("access to '/etc/passwd' blocked: absolute paths are not allowed"))
+        );
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_get_data() {
+        let dir = create_tree("get_data");
+        let source = FileDatasource::new(&dir.join("root")).unwrap();
+
+        assert_eq!(read_all(source.get_data("main.fgu").unwrap()), "main");
+        assert_eq!(read_all(source.get_data("sub/lib.fgu").unwrap()), "lib");
+        assert!(source.get_data("doesnotexist.fgu").is_err());
+        assert!(source.get_data("../secret.fgu").is_err());
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_preprocessor_include() {
+        let dir = create_tree("preprocessor");
+        fs::write(dir.join("root/sub/doc.fgu"), "/include lib.fgu\n/include ../../secret.fgu").unwrap();
+        let source = FileDatasource::new(&dir.join("root")).unwrap();
+
+        let output: Vec<Result<LineWithContext, FoggyError>> = Preprocessor::new(&source, &["sub/doc.fgu"]).collect();
+        assert_eq!(output.len(), 2);
+        assert_eq!(output[0].as_ref().unwrap().text, "lib");
+        assert_eq!(output[0].as_ref().unwrap().file_name.as_str(), "sub/lib.fgu");
+        assert_eq!(
+            output[1].as_ref().unwrap_err().to_string(),
+            "sub/doc.fgu:2:1: access to '../../secret.fgu' blocked: path is outside of the root directory"
+        );
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_symlink_out_of_root() {
+        let dir = create_tree("symlink");
+        std::os::unix::fs::symlink(dir.join("secret.fgu"), dir.join("root/link.fgu")).unwrap();
+        std::os::unix::fs::symlink(dir.join("root/main.fgu"), dir.join("root/sub/inner.fgu")).unwrap();
+        let source = FileDatasource::new(&dir.join("root")).unwrap();
+
+        match source.get_data("link.fgu") {
+            Ok(_) => assert!(false),
+            Err(e) => assert_eq!(e.kind, ErrorKind::AccessDenied(
+                String::from("access to 'link.fgu' blocked: file is outside of the root directory"))),
+        }
+        // links that stay in the tree are fine
+        assert_eq!(read_all(source.get_data("sub/inner.fgu").unwrap()), "main");
+        let _ = fs::remove_dir_all(&dir);
+    }
+}
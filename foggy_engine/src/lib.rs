@@ -1,8 +1,10 @@
 pub mod datatypes;
+pub mod errors;
 pub mod parsers;
 pub mod builders;
 pub mod parseutils;
 pub mod preprocessor;
+pub mod filedatasource;
 
 #[cfg(test)]
 mod tests {
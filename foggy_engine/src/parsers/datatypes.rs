@@ -1,6 +1,7 @@
 use std::rc::Rc;
 use std::cell::{RefCell};
 use crate::datatypes::{SliceWithContext, Element, Document};
+use crate::errors::FoggyError;
 
 
 
@@ -20,10 +21,15 @@ pub trait Parser {
     fn step<'a>(
         &mut self,
         input: &'a mut SliceWithContext<'a>,
-    ) -> Result<ParserResult<'a>, (&'a SliceWithContext<'a>, String)>;
+    ) -> Result<ParserResult<'a>, FoggyError>;
     // flush must be called only after all the data has been fed to the parser
     // -> parser returned Done
     // -> combinator found that the context of this parser is finished (end token or start token of an other parser)
     // After flush is called, I consider that the parser cannot be used anymore
     fn flush(&mut self) -> (Vec<Rc<RefCell<Element>>>, Vec<Rc<RefCell<Document>>>);
+    // called when the combinator closes the block on its end token, before flush.
+    // The parser can report what is still open, eg: a fragment without 'end'
+    fn end(&mut self) -> Result<(), FoggyError> {
+        Ok(())
+    }
 }
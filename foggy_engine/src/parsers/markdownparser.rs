@@ -1,8 +1,9 @@
 use std::rc::Rc;
 use std::cell::{RefCell};
-use crate::datatypes::{SliceWithContext, ElementContent, Element, Document};
+use crate::datatypes::{SliceWithContext, ElementContent, Element, Document, SourceSpan};
 use crate::parsers::datatypes::{Parser, ParserResult};
 use crate::parseutils::*;
+use crate::errors::{ErrorKind, FoggyError};
 
 #[derive(PartialEq)]
 enum MDCloseCondition {
@@ -15,6 +16,16 @@ enum MDCloseCondition {
 }
 
 
+// A fenced code block being parsed:
+// ```python
+// print("hello")
+// ```
+struct CodeBlock {
+    fence: usize, // number of backticks of the opening fence
+    element: Rc<RefCell<Element>>,
+    text: String,
+}
+
 pub struct MarkdownParser {
     // a string buffer to collect text for the element being parsed
     collec: Option<String>,
@@ -22,6 +33,10 @@ pub struct MarkdownParser {
     root: Element,
     // indicates the current depth in the element branches, with associated closing conditions
     open_tokens: Vec<(Rc<RefCell<Element>>,MDCloseCondition)>,
+    // lines are taken verbatim until the closing fence
+    code: Option<CodeBlock>,
+    // start of the line being parsed, for the blocks it opens
+    source: Option<SourceSpan>,
 }
 // Principle of operation is simple:
 // we are always writing (adding children) at the end of the element tree designated by self.root.
@@ -44,6 +59,37 @@ impl MarkdownParser {
             collec: Some(String::new()),
             root: Element::new("text:body"),
             open_tokens: Vec::new(),
+            code: None,
+            source: None,
+        }
+    }
+
+    // ``` or more, returns the number of backticks
+    fn fence_length(input: &str) -> usize {
+        let (_, fence) = consume_while_char(input, '`');
+        return if fence.len() >= 3 { fence.len() } else { 0 };
+    }
+
+    fn push_code(&mut self, fence: usize, language: &str) {
+        // Like titles, code blocks are children of the document root
+        self.collect_all_open_tokens();
+
+        let mut element = Element::new("code");
+        element.source = self.source.clone();
+        if language.len() > 0 {
+            element.push_attribute("language", language);
+        }
+
+        let ptr=Rc::new(RefCell::new(element));
+        self.root.push(Rc::clone(&ptr));
+        self.code = Some(CodeBlock { fence, element: ptr, text: String::new() });
+    }
+
+    fn collect_code(&mut self) {
+        if let Some(code) = self.code.take() {
+            if code.text.len() > 0 {
+                code.element.borrow_mut().push(Rc::new(RefCell::new(Element::str(&code.text))));
+            }
         }
     }
 
@@ -52,9 +98,10 @@ impl MarkdownParser {
         // So we haver to close any open token that we may have
         self.collect_all_open_tokens();
 
-        let element = Element::new("format")
+        let mut element = Element::new("format")
             .attr("format", "title")
             .attr("level", &level.to_string());
+        element.source = self.source.clone();
 
         let ptr=Rc::new(RefCell::new(element));
         self.root.push(Rc::clone(&ptr));
@@ -72,8 +119,9 @@ impl MarkdownParser {
     }
 
     fn push_paragraph(&mut self) {
-        let element = Element::new("format")
+        let mut element = Element::new("format")
             .attr("format", "paragraph");
+        element.source = self.source.clone();
 
         let ptr=Rc::new(RefCell::new(element));
         self.collect_to_last_leaf();
@@ -142,12 +190,40 @@ impl Parser for MarkdownParser{
     fn step<'b>(
         &mut self,
         input: &'b mut SliceWithContext<'b>,
-    ) -> Result<ParserResult<'b>, (&'b SliceWithContext<'b>, String)> {
+    ) -> Result<ParserResult<'b>, FoggyError> {
         let mut slice: &str = input.slice;
 
         // Shall not happen
         if self.collec == None {
-            return Err((input, String::from("runtime error, collector shall not be none")));
+            return Err(input.error_at(slice, ErrorKind::Internal(String::from("collector shall not be none"))));
+        }
+
+        // Inside a code block, nothing is interpreted until the closing fence,
+        // that is at least as long as the opening one
+        if let Some(code) = self.code.as_mut() {
+            let trimmed = slice.trim();
+            if Self::fence_length(trimmed) >= code.fence && trimmed.chars().all(|c| c == '`') {
+                self.collect_code();
+                input.slice = &slice[slice.len()..];
+                return Ok(ParserResult::Partial(input));
+            }
+            if code.text.len() > 0 {
+                code.text.push('\n');
+            }
+            code.text.push_str(slice);
+            input.slice = &slice[slice.len()..];
+            return Ok(ParserResult::Busy);
+        }
+        self.source = Some(input.span_at(slice.trim_start()));
+        let fence = Self::fence_length(slice.trim_start());
+        if fence > 0 {
+            let language = slice.trim()[fence..].trim();
+            if language.contains('`') {
+                return Err(input.error_at(slice, ErrorKind::Syntax(String::from("invalid code block language"))));
+            }
+            self.push_code(fence, language);
+            input.slice = &slice[slice.len()..];
+            return Ok(ParserResult::Busy);
         }
 
         // Check for tokens that are always at the start of line
@@ -157,7 +233,7 @@ impl Parser for MarkdownParser{
                 "###" => self.push_title(3),
                 "##" => self.push_title(2),
                 "#" => self.push_title(1),
-                _ => return Err((input, String::from("runtime error, invalid condition"))),
+                _ => return Err(input.error_at(slice, ErrorKind::Internal(String::from("invalid condition")))),
             }
             let (new_slice, _) = consume_whitespaces(new_slice);
             slice = new_slice;
@@ -203,10 +279,8 @@ impl Parser for MarkdownParser{
                     "**" => self.push_formatter("**", "bold"),
                     "*" => self.push_formatter("*", "italic"),
                     "~~" => self.push_formatter("~~", "strikethrough"),
-                    _ => return Err((
-                        input,
-                        String::from("runtime error, met unexpected token"),
-                    )) // no other token apart from those in stop_tokens array shall be consumed
+                    _ => return Err(input.error_at(slice, ErrorKind::Internal(String::from("met unexpected token"))))
+                    // no other token apart from those in stop_tokens array shall be consumed
                 }
             }
             slice=new_slice;
@@ -225,6 +299,7 @@ impl Parser for MarkdownParser{
     }
 
     fn flush(&mut self) -> (Vec<Rc<RefCell<Element>>>, Vec<Rc<RefCell<Document>>>) {
+        self.collect_code();
         self.collect_all_open_tokens();
         if let ElementContent::Tree(ref content) =self.root.content{
             return (content.children.clone(), vec![]);
@@ -256,6 +331,7 @@ mod tests {
             line: 0,
             pos: 0,
             file_name: Rc::new(String::from("file.txt")),
+            namespace: Rc::new(String::new()),
         };
 
         let returned = parser.step(&mut slice);
@@ -289,6 +365,7 @@ mod tests {
             line: 0,
             pos: 0,
             file_name: Rc::new(String::from("file.txt")),
+            namespace: Rc::new(String::new()),
         };
 
         let returned = parser.step(&mut slice);
@@ -313,6 +390,7 @@ mod tests {
             line: 0,
             pos: 0,
             file_name: Rc::new(String::from("file.txt")),
+            namespace: Rc::new(String::new()),
         };
 
         let returned = parser.step(&mut slice);
@@ -346,6 +424,7 @@ mod tests {
             line: 0,
             pos: 0,
             file_name: Rc::new(String::from("file.txt")),
+            namespace: Rc::new(String::new()),
         };
         match parser.step(&mut slice).unwrap() {
             // Check that everything was consumed
@@ -358,6 +437,7 @@ mod tests {
             line: 0,
             pos: 0,
             file_name: Rc::new(String::from("file.txt")),
+            namespace: Rc::new(String::new()),
         };
         match parser.step(&mut slice2).unwrap() {
             // Check that everything was consumed
@@ -388,6 +468,7 @@ mod tests {
             line: 0,
             pos: 0,
             file_name: Rc::new(String::from("file.txt")),
+            namespace: Rc::new(String::new()),
         };
 
         let returned = parser.step(&mut slice);
@@ -422,6 +503,7 @@ mod tests {
             line: 0,
             pos: 0,
             file_name: Rc::new(String::from("file.txt")),
+            namespace: Rc::new(String::new()),
         };
 
         let returned = parser.step(&mut slice);
@@ -460,6 +542,7 @@ mod tests {
             line: 0,
             pos: 0,
             file_name: Rc::new(String::from("file.txt")),
+            namespace: Rc::new(String::new()),
         };
         match parser.step(&mut slice).unwrap() {
             // Check that everything was consumed
@@ -472,6 +555,7 @@ mod tests {
             line: 0,
             pos: 0,
             file_name: Rc::new(String::from("file.txt")),
+            namespace: Rc::new(String::new()),
         };
         match parser.step(&mut slice2).unwrap() {
             // Check that everything was consumed
@@ -500,4 +584,43 @@ mod tests {
         assert_eq!(elements, expected);
     }
 
-}
\ No newline at end of file
+    #[test]
+    fn test_markdownparser_code() {
+        let mut parser = MarkdownParser::new();
+        let file_name = Rc::new(String::from("file.txt"));
+
+        let lines = ["text", "````python", "# **not a title**", "```", "", "````", "after"];
+        let results: Vec<bool> = lines.iter().map(|l| {
+            let mut slice = SliceWithContext {
+                slice: l,
+                line: 0,
+                pos: 0,
+                file_name: Rc::clone(&file_name),
+                namespace: Rc::new(String::new()),
+            };
+            match parser.step(&mut slice).unwrap() {
+                ParserResult::Busy => true,
+                _ => false,
+            }
+        }).collect();
+        // busy until the fence with the same length
+        assert_eq!(results, vec![false, true, true, true, true, false, false]);
+
+        let (elements, _) = parser.flush();
+        let expected:Vec<Rcc<Element>>=vec![
+            rcc(Element::new("format")
+                .attr("format", "paragraph")
+                .child(Element::str("text"))
+            ),
+            rcc(Element::new("code")
+                .attr("language", "python")
+                .child(Element::str("# **not a title**\n```\n"))
+            ),
+            rcc(Element::new("format")
+                .attr("format", "paragraph")
+                .child(Element::str("after"))
+            ),
+        ];
+        assert_eq!(elements, expected);
+    }
+}
@@ -1,27 +1,456 @@
-use crate::datatypes::{LineWithContext, Document};
-//use parsers::stringparser::StringParser;
+use std::rc::Rc;
+use crate::datatypes::{LineWithContext, SliceWithContext, Element, Document, Rcc, rcc};
+use crate::parsers::datatypes::{Parser, ParserResult};
+use crate::parsers::markdownparser::MarkdownParser;
+use crate::parsers::stringparser::StringParser;
+use crate::parsers::sequenceparser::SequenceDiagramParser;
+use crate::parsers::stringparseutils::starts_with_token;
+use crate::parseutils::consume_token_in_list;
+use crate::errors::{ErrorKind, FoggyError};
 
+// The combinator reads the preprocessed lines and decides which parser shall consume them.
+// By default, lines are text and are fed to the markdown parser.
+// A line that starts with the start token of an other parser opens a block:
+// the following lines are fed to that parser until it returns Done
+// or until the end token of the block is met.
+//
+// Each top level block becomes a document, in source order:
+// - consecutive text is collected in one document, under a single text:body element
+// - each diagram is a document on its own
+//
+// A diagram can also be embedded in the text with a fenced block:
+// ```sequence
+// alice -> bob
+// ```
+// In that case the diagram elements are inserted in the text:body tree,
+// at the position of the block, and the text document goes on after it.
+// Other fenced blocks are code, handled by the markdown parser.
 
-struct ParserCombinator {}
+#[derive(Copy, Clone, PartialEq)]
+enum BlockType {
+    Text,
+    Literal,  // """string""" at the start of a text line
+    Sequence, // @startuml ... @enduml
+    FencedSequence, // ```sequence ... ``` inside text
+}
+
+pub struct ParserCombinator {
+    // parser of the currently open block
+    parser: Box<dyn Parser>,
+    block: BlockType,
+    // where the current block was opened, for error reporting
+    block_start: Option<(Rc<String>, u32)>,
+    // the last step returned Busy, the parser wants more lines before anything else happens
+    busy: bool,
+    // elements already flushed for the text document being built
+    text_elements: Vec<Rcc<Element>>,
+    documents: Vec<Document>,
+}
 
 impl ParserCombinator {
-    fn interpret(
-        input: &mut impl Iterator<Item = Result<LineWithContext, &'static str>>,
-    ) -> Result<Vec<Document>, &'static str> {
-        // let mut parser = StringParser::new();
-
-        // while let Some(line) = input.next() {
-        //     let line_content = line.unwrap();
-
-        //     let mut slice = SliceWithContext {
-        //         slice: &line_content.text,
-        //         line: line_content.line,
-        //         pos: 0,
-        //         file_name: Rc::clone(&line_content.file_name),
-        //     };
-        //     parser.step(&mut slice);
-        // }
-
-        return Err("tu vas compiler oui");
-    }
-}
\ No newline at end of file
+    fn new() -> ParserCombinator {
+        ParserCombinator {
+            parser: Box::new(MarkdownParser::new()),
+            block: BlockType::Text,
+            block_start: None,
+            busy: false,
+            text_elements: vec![],
+            documents: vec![],
+        }
+    }
+
+    pub fn interpret(
+        input: &mut impl Iterator<Item = Result<LineWithContext, FoggyError>>,
+    ) -> Result<Vec<Document>, FoggyError> {
+        let mut combinator = ParserCombinator::new();
+
+        while let Some(line) = input.next() {
+            let line_content = line?;
+            if let Err(e) = combinator.step_line(&line_content) {
+                return match &line_content.note {
+                    // the column is in the expanded text, not in the source
+                    Some(note) => Err(FoggyError { column: 0, ..e.with_note(note) }),
+                    None => Err(e),
+                };
+            }
+        }
+        return combinator.finish();
+    }
+
+    fn step_line(&mut self, line: &LineWithContext) -> Result<(), FoggyError> {
+        let text = line.text.as_str();
+
+        match self.block {
+            // a busy markdown parser is in a code block, lines are not interpreted
+            BlockType::Text if self.busy => (),
+            BlockType::Text => {
+                if SequenceDiagramParser::is_start_word(text) {
+                    // the diagram is a document on its own, close the text document first
+                    self.close_block();
+                    self.push_text_document();
+                    self.open_block(BlockType::Sequence, Box::new(SequenceDiagramParser::new()), line);
+                    return Ok(());
+                }
+                if Self::is_fence_start(text, "sequence") {
+                    self.close_block();
+                    self.open_block(BlockType::FencedSequence, Box::new(SequenceDiagramParser::new()), line);
+                    return Ok(());
+                }
+                if starts_with_token(text, Self::get_literal_start_words()) {
+                    // the string parser consumes only a part of the line,
+                    // the rest of the line is fed to a new markdown parser when it is Done
+                    self.close_block();
+                    self.open_block(BlockType::Literal, Box::new(StringParser::new()), line);
+                }
+            }
+            BlockType::Sequence => {
+                if !self.busy && SequenceDiagramParser::is_end_word(text) {
+                    self.parser.end()?;
+                    self.close_block();
+                    return Ok(());
+                }
+            }
+            BlockType::FencedSequence => {
+                if !self.busy && Self::is_fence_end(text) {
+                    self.parser.end()?;
+                    self.close_block();
+                    return Ok(());
+                }
+            }
+            BlockType::Literal => (),
+        }
+        return self.feed(line, 0);
+    }
+
+    // feed the rest of the line, starting at byte offset pos, to the parser of the current block
+    fn feed(&mut self, line: &LineWithContext, pos: usize) -> Result<(), FoggyError> {
+        let mut slice = SliceWithContext {
+            slice: &line.text[pos..],
+            line: line.line,
+            pos: pos as u32,
+            file_name: Rc::clone(&line.file_name),
+            namespace: Rc::clone(&line.namespace),
+        };
+
+        match self.parser.step(&mut slice) {
+            Ok(ParserResult::Busy) => {
+                if self.block_start == None {
+                    // text has no start token, the block starts where the parser gets busy
+                    self.block_start = Some((Rc::clone(&line.file_name), line.line));
+                }
+                self.busy = true;
+            }
+            Ok(ParserResult::Partial(_)) => {
+                if self.block == BlockType::Text {
+                    self.block_start = None;
+                }
+                self.busy = false;
+            }
+            Ok(ParserResult::Done(s)) => {
+                // The parser met its own end token, the block is finished.
+                // Whatever remains on the line belongs to the enclosing text
+                let offset = line.text.len() - s.slice.len();
+                self.close_block();
+                if offset < line.text.len() {
+                    return self.feed(line, offset);
+                }
+            }
+            Err(e) => return Err(e),
+        }
+        return Ok(());
+    }
+
+    fn open_block(&mut self, block: BlockType, parser: Box<dyn Parser>, line: &LineWithContext) {
+        self.parser = parser;
+        self.block = block;
+        self.block_start = Some((Rc::clone(&line.file_name), line.line));
+        self.busy = false;
+    }
+
+    // flush the parser of the current block and go back to text
+    fn close_block(&mut self) {
+        let (elements, documents) = self.parser.flush();
+        match self.block {
+            BlockType::Text | BlockType::Literal | BlockType::FencedSequence
+                => self.text_elements.extend(elements),
+            BlockType::Sequence => self.documents.push(Document { children: elements }),
+        }
+        for d in documents {
+            self.documents.push(Document { children: d.borrow().children.clone() });
+        }
+
+        self.parser = Box::new(MarkdownParser::new());
+        self.block = BlockType::Text;
+        self.block_start = None;
+        self.busy = false;
+    }
+
+    fn push_text_document(&mut self) {
+        if self.text_elements.len() == 0 {
+            return;
+        }
+        let mut body = Element::new("text:body");
+        for elt in self.text_elements.drain(..) {
+            body.push(elt);
+        }
+        self.documents.push(Document { children: vec![rcc(body)] });
+    }
+
+    fn finish(mut self) -> Result<Vec<Document>, FoggyError> {
+        if self.block != BlockType::Text || self.busy {
+            // the parser may know better what is not closed, like a note in a diagram
+            self.parser.end()?;
+            if let Some((file_name, line)) = &self.block_start {
+                return Err(FoggyError::at(ErrorKind::UnclosedBlock, file_name, *line, 0));
+            }
+            return Err(FoggyError::new(ErrorKind::UnexpectedEndOfInput));
+        }
+        self.close_block();
+        self.push_text_document();
+        return Ok(self.documents);
+    }
+
+    // ```tag, with nothing else than whitespaces after the tag
+    fn is_fence_start(input: &str, tag: &str) -> bool {
+        let fence = format!("```{}", tag);
+        match consume_token_in_list(input.trim_start(), &[fence.as_str()]) {
+            Ok((remaining, _)) => remaining.trim().len() == 0,
+            Err(_) => false,
+        }
+    }
+
+    fn is_fence_end(input: &str) -> bool {
+        input.trim() == "```"
+    }
+
+    fn get_literal_start_words() -> &'static [&'static str] {
+        // Only triple quotes, a text line starting with a simple quote is just text
+        return &["\"\"\""];
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::datatypes::*;
+    use std::rc::Rc;
+
+    fn lines(text: &'static str) -> Vec<Result<LineWithContext, FoggyError>> {
+        let file_name = Rc::new(String::from("file.fgu"));
+        text.lines().enumerate().map(|(i, l)| Ok(LineWithContext {
+            text: String::from(l),
+            line: i as u32 + 1,
+            file_name: Rc::clone(&file_name),
+            namespace: Rc::new(String::new()),
+            note: None,
+        })).collect()
+    }
+
+    #[test]
+    fn test_combinator_text() {
+        let input = lines("# Title\nline 1");
+        let documents = ParserCombinator::interpret(&mut input.into_iter()).unwrap();
+        assert_eq!(documents.len(), 1);
+
+        let expected: Vec<Rcc<Element>> = vec![
+            rcc(Element::new("text:body")
+                .child(Element::new("format")
+                    .attr("format", "title")
+                    .attr("level", "1")
+                    .child(Element::str("Title"))
+                )
+                .child(Element::new("format")
+                    .attr("format", "paragraph")
+                    .child(Element::str("line 1"))
+                )
+            ),
+        ];
+        assert_eq!(documents[0].children, expected);
+    }
+
+    #[test]
+    fn test_combinator_sequence() {
+        let input = lines("intro\n@startuml\nalice->bob\n@enduml\noutro");
+        let documents = ParserCombinator::interpret(&mut input.into_iter()).unwrap();
+        assert_eq!(documents.len(), 3);
+
+        assert_eq!(documents[0].children[0].borrow().get_tag(), "text:body");
+        assert_eq!(documents[1].children.len(), 2);
+        assert_eq!(documents[1].children[0].borrow().get_tag(), "sequencediagram:header");
+        assert_eq!(documents[1].children[1].borrow().get_tag(), "sequencediagram:content");
+        assert_eq!(documents[2].children[0].borrow().get_tag(), "text:body");
+    }
+
+    #[test]
+    fn test_combinator_fenced_sequence() {
+        let input = lines("intro\n```sequence\nalice->bob\n```\noutro");
+        let documents = ParserCombinator::interpret(&mut input.into_iter()).unwrap();
+        assert_eq!(documents.len(), 1);
+
+        let expected: Vec<Rcc<Element>> = vec![
+            rcc(Element::new("text:body")
+                .child(Element::new("format")
+                    .attr("format", "paragraph")
+                    .child(Element::str("intro"))
+                )
+                .child(Element::new("sequencediagram:header")
+                    .child(Element::new("participant")
+                        .attr("alias", "alice")
+                        .child(Element::new("name")
+                            .child(Element::str("alice"))
+                        )
+                    )
+                    .child(Element::new("participant")
+                        .attr("alias", "bob")
+                        .child(Element::new("name")
+                            .child(Element::str("bob"))
+                        )
+                    )
+                )
+                .child(Element::new("sequencediagram:content")
+                    .child(Element::new("arrow")
+                        .attr("origin", "alice")
+                        .attr("target", "bob")
+                        .attr("line-style", "normal")
+                        .attr("arrow-style", "normal")
+                    )
+                )
+                .child(Element::new("format")
+                    .attr("format", "paragraph")
+                    .child(Element::str("outro"))
+                )
+            ),
+        ];
+        assert_eq!(documents[0].children, expected);
+    }
+
+    #[test]
+    fn test_combinator_fence_tag() {
+        assert!(ParserCombinator::is_fence_start("```sequence", "sequence"));
+        assert!(ParserCombinator::is_fence_start("  ```sequence  ", "sequence"));
+        assert!(!ParserCombinator::is_fence_start("```sequences", "sequence"));
+        assert!(!ParserCombinator::is_fence_start("```", "sequence"));
+        assert!(ParserCombinator::is_fence_end(" ``` "));
+        assert!(!ParserCombinator::is_fence_end("```sequence"));
+    }
+
+    #[test]
+    fn test_combinator_unterminated_fence() {
+        let input = lines("text\n\n```sequence\nalice->bob");
+        let result = ParserCombinator::interpret(&mut input.into_iter());
+        assert_eq!(result.unwrap_err().to_string(), "file.fgu:3: block is not closed");
+    }
+
+    #[test]
+    fn test_combinator_literal() {
+        let input = lines("\"\"\"**not bold**\"\"\" but *italic*");
+        let documents = ParserCombinator::interpret(&mut input.into_iter()).unwrap();
+        assert_eq!(documents.len(), 1);
+
+        let expected: Vec<Rcc<Element>> = vec![
+            rcc(Element::new("text:body")
+                .child(Element::str("**not bold**"))
+                .child(Element::new("format")
+                    .attr("format", "paragraph")
+                    .child(Element::str(" but "))
+                    .child(Element::new("format")
+                        .attr("format", "italic")
+                        .child(Element::str("italic"))
+                    )
+                )
+            ),
+        ];
+        assert_eq!(documents[0].children, expected);
+    }
+
+    #[test]
+    fn test_combinator_unterminated_block() {
+        let input = lines("@startuml\nalice->bob");
+        let result = ParserCombinator::interpret(&mut input.into_iter());
+        assert_eq!(result.unwrap_err().to_string(), "file.fgu:1: block is not closed");
+    }
+
+    #[test]
+    fn test_combinator_unterminated_note() {
+        let input = lines("```sequence\nalice->bob\nnote over bob\ntext\n```");
+        let result = ParserCombinator::interpret(&mut input.into_iter());
+        assert_eq!(result.unwrap_err().to_string(), "file.fgu:3:1: block is not closed");
+    }
+
+    #[test]
+    fn test_combinator_parser_error() {
+        let input = lines("@startuml\nparticipant alice\nalice -> \"bob\n@enduml");
+        let result = ParserCombinator::interpret(&mut input.into_iter());
+        assert_eq!(result.unwrap_err().to_string(), "file.fgu:3:10: unfinished string");
+    }
+
+    #[test]
+    fn test_combinator_preprocessor_error() {
+        let input: Vec<Result<LineWithContext, FoggyError>> = vec![Err(FoggyError::new(ErrorKind::Io(String::from("File does not exist"))))];
+        let result = ParserCombinator::interpret(&mut input.into_iter());
+        assert_eq!(result.unwrap_err().to_string(), "File does not exist");
+    }
+
+    #[test]
+    fn test_combinator_code() {
+        let input = lines("```text\n@startuml\n\"\"\"\n```\nafter");
+        let documents = ParserCombinator::interpret(&mut input.into_iter()).unwrap();
+        assert_eq!(documents.len(), 1);
+
+        let expected: Vec<Rcc<Element>> = vec![
+            rcc(Element::new("text:body")
+                .child(Element::new("code")
+                    .attr("language", "text")
+                    .child(Element::str("@startuml\n\"\"\""))
+                )
+                .child(Element::new("format")
+                    .attr("format", "paragraph")
+                    .child(Element::str("after"))
+                )
+            ),
+        ];
+        assert_eq!(documents[0].children, expected);
+
+        let input = lines("text\n```\ncode");
+        let result = ParserCombinator::interpret(&mut input.into_iter());
+        assert_eq!(result.unwrap_err().to_string(), "file.fgu:2: block is not closed");
+    }
+
+    #[test]
+    fn test_combinator_macro_error() {
+        let mut input = lines("@startuml\nalice -> \"bob\n@enduml");
+        if let Ok(line) = &mut input[1] {
+            line.note = Some(Rc::new(String::from("expanded from macro 'call' defined at lib.fgu:3")));
+        }
+        let result = ParserCombinator::interpret(&mut input.into_iter());
+        assert_eq!(
+            result.unwrap_err().to_string(),
+            "file.fgu:2: unfinished string (expanded from macro 'call' defined at lib.fgu:3)"
+        );
+    }
+
+    #[test]
+    fn test_combinator_source() {
+        let input = lines("# Title\n@startuml\nparticipant alice\n  alice -> bob\n@enduml");
+        let documents = ParserCombinator::interpret(&mut input.into_iter()).unwrap();
+
+        let source = |elt: &Rcc<Element>| elt.borrow().source.as_ref().map(|s| s.to_string());
+        let child = |elt: &Rcc<Element>, path: &str| match elt.borrow().get(path) {
+            Some(QueryResult::Elt(e)) => e,
+            _ => panic!("no {}", path),
+        };
+
+        let body = &documents[0].children[0];
+        assert_eq!(source(&child(body, "format/")), Some(String::from("file.fgu:1:1")));
+
+        let header = &documents[1].children[0];
+        let content = &documents[1].children[1];
+        if let ElementContent::Tree(c) = &header.borrow().content {
+            let sources: Vec<Option<String>> = c.children.iter().map(|p| source(p)).collect();
+            // bob is declared by the arrow
+            assert_eq!(sources, vec![Some(String::from("file.fgu:3:1")), Some(String::from("file.fgu:4:3"))]);
+        }
+        assert_eq!(source(&child(content, "arrow/")), Some(String::from("file.fgu:4:3")));
+    }
+}
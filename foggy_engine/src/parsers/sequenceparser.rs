@@ -2,9 +2,11 @@ use std::rc::Rc;
 use std::cell::{RefCell};
 use crate::datatypes::*;
 use crate::parsers::datatypes::{Parser, ParserResult};
+use crate::parsers::markdownparser::MarkdownParser;
 use crate::parseutils::*;
 use crate::parsers::stringparseutils::*;
-use std::collections::HashMap;
+use crate::errors::{ErrorKind, FoggyError};
+use std::collections::{HashMap, HashSet};
 
 use maplit::hashmap;
 
@@ -22,7 +24,7 @@ use maplit::hashmap;
 // - state changes
 // - vertical sepration
 
-static RESERVED_TOKENS_HEADER: [&'static str;12] = [
+static RESERVED_TOKENS_HEADER: [&'static str;13] = [
     //actors definitions in header
     "participant",
     "actor", 
@@ -37,27 +39,32 @@ static RESERVED_TOKENS_HEADER: [&'static str;12] = [
     "title",
     "hide",
     "show",
+    // also in the sequence, it usually comes before the participants
+    "autonumber",
     ];
 
-static RESERVED_TOKENS_SEQUENCE: [&'static str;18] = [
+static RESERVED_TOKENS_SEQUENCE: [&'static str;21] = [
     "alt",
     "else",
     "group",
     "loop",
+    "opt",
+    "par",
     "end",
     "ref",
     "note",
     "rnote", 
     "hnote",
     "activate",
+    "create",
     "destroy",
     "deactivate",
-    "deactivate",
     "return",
     "...",
     "|||",
     "||",
     "==",
+    "autonumber",
 ];
 
 
@@ -99,6 +106,18 @@ enum ArrowDecor{
 
 
 
+// Errors found while parsing a line: where (the end of the line, starting at the faulty part) and what
+type ParseError<'s> = (&'s str, ErrorKind);
+
+
+// 'autonumber': number of the next message, increment, and how the number is written
+struct Autonumber{
+    next: u32,
+    step: u32,
+    format: String,
+    running: bool,
+}
+
 #[derive(PartialEq)]
 enum SequenceDiagramParserState{
     Header,
@@ -116,11 +135,33 @@ pub struct SequenceDiagramParser {
     collec: Option<String>,
     // full tree structure of document
     title: Option<Element>,
+    // 'hide footbox' and 'hide unlinked', as attributes of the header
+    options: Vec<(String, String)>,
     header: Vec<Rc<RefCell<Element>>>, //header root element
     sequence: Vec<Rc<RefCell<Element>>>,
     state: SequenceDiagramParserState,
     open_header_tokens: Vec<(Rc<RefCell<Element>>, HDCloseCondition)>,
+    // participants by alias, qualified with their namespace: lib.alice
     participants_map: std::collections::HashMap<String, Rc<RefCell<Element>>>, 
+    // namespace of the line being parsed
+    namespace: Rc<String>,
+    // start of the statement being parsed, for the elements it creates
+    source: Option<SourceSpan>,
+    // open fragments (alt, loop...) with their current section, content goes to the last one
+    open_fragments: Vec<(Rcc<Element>, Rcc<Element>)>,
+    // active participants, with the origin of the message that activated them, for 'return'
+    activations: Vec<(String, Option<String>)>,
+    // origin and target of the last message
+    last_message: Option<(String, String)>,
+    // participants that appeared in the sequence so far, and the destroyed ones
+    in_sequence: HashSet<String>,
+    destroyed: HashSet<String>,
+    // multi-line note being parsed, its text is markdown
+    note: Option<(Rcc<Element>, MarkdownParser)>,
+    // numbering of the messages, from the first 'autonumber'
+    autonumber: Option<Autonumber>,
+    // '{id}' of the messages so far, for the constraints
+    message_ids: HashSet<String>,
 }
 
 
@@ -129,17 +170,41 @@ impl SequenceDiagramParser {
         SequenceDiagramParser {
             collec: Some(String::new()),
             title:None,
+            options: vec![],
             header:vec![],
             sequence: vec![],
             state: SequenceDiagramParserState::Header,
             open_header_tokens: vec![],
-            participants_map: HashMap::new(), 
-
+            participants_map: HashMap::new(),
+            namespace: Rc::new(String::new()),
+            source: None,
+            open_fragments: vec![],
+            activations: vec![],
+            last_message: None,
+            in_sequence: HashSet::new(),
+            destroyed: HashSet::new(),
+            note: None,
+            autonumber: None,
+            message_ids: HashSet::new(),
         }
     }
 
-    fn add_participant<'a>(&mut self, input: &str, participant_token: &str)
-        -> Result<(), String>{
+    pub fn is_start_word(input: &str) -> bool {
+        starts_with_token(input.trim_start(), Self::get_start_words())
+    }
+    pub fn get_start_words() -> &'static [&'static str] {
+        return &["@startuml"];
+    }
+
+    pub fn is_end_word(input: &str) -> bool {
+        starts_with_token(input.trim_start(), Self::get_end_words())
+    }
+    pub fn get_end_words() -> &'static [&'static str] {
+        return &["@enduml"];
+    }
+
+    fn add_participant<'a>(&mut self, input: &'a str, participant_token: &str)
+        -> Result<(), ParseError<'a>>{
 
         let mut slice=input;
         let mut name_element: Option<Rcc<Element>>=None;
@@ -150,7 +215,7 @@ impl SequenceDiagramParser {
         {
             let (new_slice, spaces) = consume_whitespaces(slice);
             if spaces.len()==0{
-                return Err(String::from("Expecting spaces"));
+                return Err((slice, ErrorKind::Syntax(String::from("Expecting spaces"))));
             }
             slice = new_slice;
         }
@@ -170,7 +235,7 @@ impl SequenceDiagramParser {
         {
             let (new_slice, spaces) = consume_whitespaces(slice);
             if spaces.len()==0 && new_slice.len() > 0{
-                return Err(String::from("Expecting spaces before 'as' token"));
+                return Err((slice, ErrorKind::Syntax(String::from("Expecting spaces before 'as' token"))));
             }
             slice = new_slice;
         }
@@ -179,7 +244,8 @@ impl SequenceDiagramParser {
         if slice.len()>0{
             let res = consume_token_in_list(slice, &["as"]);
             if res.is_err() {
-                return Err(String::from("Unexpected token"));
+                let (_, token) = consume_until_whitespace(slice).unwrap();
+                return Err((slice, ErrorKind::UnexpectedToken(String::from(token))));
             }
             else{
                 let (remaining, _)=res.unwrap(); 
@@ -189,7 +255,7 @@ impl SequenceDiagramParser {
             {
                 let (new_slice, spaces) = consume_whitespaces(slice);
                 if spaces.len()==0 && new_slice.len() > 0{
-                    return Err(String::from("Expecting spaces after 'as' token"));
+                    return Err((slice, ErrorKind::Syntax(String::from("Expecting spaces after 'as' token"))));
                 }
                 slice = new_slice;
             } 
@@ -198,7 +264,7 @@ impl SequenceDiagramParser {
                 let (remaining, alias) =consume_until_whitespace(slice).unwrap();
                 slice = remaining;
                 if alias.len()==0{
-                    return Err(String::from("Expecting value for alias name"));
+                    return Err((slice, ErrorKind::Syntax(String::from("Expecting value for alias name"))));
                 }
                 alias_name=Some(String::from(alias));
             }
@@ -206,15 +272,16 @@ impl SequenceDiagramParser {
 
         // Build participant element and push it to header
         let mut participant_element = Element::new(participant_token);
+        participant_element.source = self.source.clone();
 
 
         match alias_name{
             // alias name provided with 'as'
-            Some(alias) => participant_element.push_attribute("alias", &alias),
+            Some(alias) => participant_element.push_attribute("alias", &self.qualify(&alias)),
             // no alias name provided, use participant name as alias
             None => if let Some(elt) = &name_element{
                 if let Some(QueryResult::Text(name)) = elt.borrow().get("text()"){
-                    participant_element.push_attribute("alias", &name);
+                    participant_element.push_attribute("alias", &self.qualify(&name));
                 }
             }
                 
@@ -226,8 +293,8 @@ impl SequenceDiagramParser {
     }
 
 
-    fn add_box<'a>(&mut self, input: &str)
-        -> Result<(), String>{
+    fn add_box<'a>(&mut self, input: &'a str)
+        -> Result<(), ParseError<'a>>{
 
         let mut slice=input;
         let mut name_element: Option<Rc<RefCell<Element>>>=None;
@@ -238,7 +305,7 @@ impl SequenceDiagramParser {
         {
             let (new_slice, spaces) = consume_whitespaces(slice);
             if spaces.len()==0{
-                return Err(String::from("Expecting spaces"));
+                return Err((slice, ErrorKind::Syntax(String::from("Expecting spaces"))));
             }
             slice = new_slice;
         }
@@ -256,17 +323,33 @@ impl SequenceDiagramParser {
         }
 
         {
-            let (new_slice, spaces) = consume_whitespaces(slice);
+            let (new_slice, _) = consume_whitespaces(slice);
             slice = new_slice;
         }
-        // Handle optional '# color' part
+        // Handle optional '# color' part: a color name, or its hex code
+        let mut color: Option<String> = None;
         if slice.len()>0{
-            // TODO
+            let (remaining, value) = match consume_token_in_list(slice, &["#"]) {
+                Ok((remaining, _)) => consume_until_whitespace(remaining).unwrap(),
+                Err(_) => return Err((slice, ErrorKind::UnexpectedToken(String::from(slice.trim())))),
+            };
+            if value.len() == 0 || !value.chars().all(|c| c.is_ascii_alphanumeric()) {
+                return Err((slice, ErrorKind::Syntax(format!("invalid color '{}'", slice.trim()))));
+            }
+            if remaining.trim().len() > 0 {
+                return Err((remaining.trim_start(), ErrorKind::UnexpectedToken(String::from(remaining.trim()))));
+            }
+            let is_hex = (value.len() == 3 || value.len() == 6) && value.chars().all(|c| c.is_ascii_hexdigit());
+            color = Some(if is_hex { format!("#{}", value) } else { value.to_lowercase() });
         }
 
 
         // Build box element ans push it to header
         let mut box_element = Element::new("box");
+        box_element.source = self.source.clone();
+        if let Some(color) = color {
+            box_element.push_attribute("color", &color);
+        }
         box_element.push(name_element.take().unwrap());
 
         let ptr = Rc::new(RefCell::new(box_element));
@@ -277,9 +360,33 @@ impl SequenceDiagramParser {
         return Ok(());
     }
 
-    fn end_box<'a>(&mut self)-> Result<(), String>{
+    // 'title text' becomes <title>text</title> at the start of the header.
+    // 'hide footbox', 'show unlinked'... become attributes of the header: footbox="hide"
+    fn add_header_option<'a>(&mut self, input: &'a str, token: &str) -> Result<(), ParseError<'a>>{
+        let slice = input.trim();
+        if token == "title" {
+            if slice.len() == 0 {
+                return Err((input, ErrorKind::Syntax(String::from("expecting title text"))));
+            }
+            let (_, text) = unescape_to_string(slice);
+            let mut title = Element::new_str("title", &text);
+            title.source = self.source.clone();
+            self.title = Some(title);
+            return Ok(());
+        }
+
+        let option = match consume_keyword_in_list(slice, &["footbox", "unlinked"]) {
+            Ok((remaining, option)) if remaining.trim().len() == 0 => option,
+            _ => return Err((input.trim_start(), ErrorKind::Syntax(format!("expecting 'footbox' or 'unlinked' after '{}'", token)))),
+        };
+        self.options.retain(|(name, _)| name != option);
+        self.options.push((String::from(option), String::from(token)));
+        return Ok(());
+    }
+
+    fn end_box<'a>(&mut self, input: &'a str)-> Result<(), ParseError<'a>>{
         if !self.is_header_close_condition(&HDCloseCondition::EndBox){
-            return Err(String::from("'end box' token not expected"));
+            return Err((input, ErrorKind::UnexpectedToken(String::from("end box"))));
         }
 
         while let Some((_, token)) = self.open_header_tokens.pop(){
@@ -294,12 +401,12 @@ impl SequenceDiagramParser {
     // Return the name as text inside an element : <name>The name</name>
     // Name isn't an attribute because it  could contain text formatting
     // TODO doesn't handle markdown formatters in name string yet
-    fn consume_name (input: &str)->Result<(&str, Rc<RefCell<Element>>), String>{
+    fn consume_name (input: &str)->Result<(&str, Rc<RefCell<Element>>), ParseError<'_>>{
         let string_tokens=["\"", "'"];
         if starts_with_token(input, &string_tokens){
             // Read participant name as a string
             match consume_between_tokens(input, &string_tokens){
-                Err(_)=> return Err(String::from("unfinished string")),
+                Err(_)=> return Err((input, ErrorKind::UnfinishedString)),
                 Ok((remaining, str_content, offset)) => {
                     let (_, string) = unescape_to_string(str_content);
                     let element = Element::new_str("name", &string);
@@ -315,10 +422,10 @@ impl SequenceDiagramParser {
             }
         }
         // should not be reached
-        return Err(String::from("unhandled"))
+        return Err((input, ErrorKind::Internal(String::from("unhandled"))))
     }
 
-    fn add_message(&mut self, input:&str)-> Result<(), String>{
+    fn add_message<'a>(&mut self, input: &'a str)-> Result<(), ParseError<'a>>{
         let mut slice=input;
         //let mut name_element: Option<Rc<RefCell<Element>>>=None;
         let mut left_name: Option<String>=None;
@@ -329,6 +436,8 @@ impl SequenceDiagramParser {
         let mut arrow_decor:Option<ArrowDecor> =None;
         let mut arrow_text:Option<String> =None;
         let mut arrow_id:Option<String> =None;
+        // '[' or ']': the message comes from or goes to the edge of the diagram
+        let mut border:Option<&str> =None;
 
         // expected format :['{'+id+'}'] + [spaces] + left_name + [spaces] + arrow + [spaces] + name +[spaces] +[ ':' +[spaces]+message]
         //                 :['{'+id+'}'] + [spaces] + '['                  + arrow + [spaces] + name +[spaces] +[ ':' +[spaces]+message]
@@ -336,13 +445,9 @@ impl SequenceDiagramParser {
 
         // First look for optional {id}
         if let Ok((_,_)) = consume_token_in_list(slice, &["{"]){
-            match consume_between_tokens(slice, &["{"]){
-                Err(_)=> return Err(String::from("unfinished {id}")),
-                Ok((remaining, content, offset)) => {
-                    arrow_id=Some(String::from(content));
-                    slice=remaining;
-                }
-            }
+            let (remaining, id) = Self::consume_id(slice)?;
+            arrow_id=Some(String::from(id));
+            slice=remaining;
         }
 
         {
@@ -350,16 +455,27 @@ impl SequenceDiagramParser {
             slice = new_slice;    
         }
 
+        // '{id} <-> {id}' is a constraint between two messages, not a message
+        if let (Some(start), Ok((remaining, _))) = (&arrow_id, consume_token_in_list(slice, &["<->"])){
+            return self.add_constraint(start, remaining);
+        }
+        if let Some(id)=&arrow_id{
+            if !self.message_ids.insert(id.clone()){
+                return Err((input, ErrorKind::Syntax(format!("message id '{}' is already used", id))));
+            }
+        }
+
 
         // Then look for either '[' or left name
         if let Ok((remaining, _)) = consume_token_in_list(slice, &["["]){
             //no left_name to parse
+            border=Some("left");
             slice=remaining;
         }
         else if let Ok((_,_)) = consume_token_in_list(slice, &["'", "\""]){
             // parse left_name name from quote-delimited string
             match consume_between_tokens(slice, &["'", "\""]){
-                Err(_)=> return Err(String::from("unfinished string")),
+                Err(_)=> return Err((slice, ErrorKind::UnfinishedString)),
                 Ok((remaining, str_content, offset)) => {
                     let (_, string) = unescape_to_string(str_content);
                     left_name = Some(string);
@@ -374,7 +490,7 @@ impl SequenceDiagramParser {
                     slice=remaining;
                     left_name = Some(String::from(parsed));
                 },
-                Err(_) => {return Err(String::from("Bad format"));},
+                Err(_) => {return Err((slice, ErrorKind::Syntax(String::from("Bad format"))));},
             }
         }
 
@@ -383,7 +499,7 @@ impl SequenceDiagramParser {
             slice = new_slice;    
         }
         if slice.len()==0 {
-            return Err(String::from("expecting arrow"))
+            return Err((slice, ErrorKind::Syntax(String::from("expecting arrow"))))
         }
         // parse arrow
         {
@@ -401,19 +517,23 @@ impl SequenceDiagramParser {
         }
 
         if slice.len()==0{
-            return Err(String::from("expecting right name"))
+            return Err((slice, ErrorKind::Syntax(String::from("expecting right name"))))
         }
 
 
         // Then look for either ']' or right name
         if let Ok((remaining, _)) = consume_token_in_list(slice, &["]"]){
-            //no left_name to parse
+            //no right_name to parse
+            if border.is_some(){
+                return Err((slice, ErrorKind::Syntax(String::from("a message can't go from a border to the other"))));
+            }
+            border=Some("right");
             slice=remaining;
         }
         else if let Ok((_,_)) = consume_token_in_list(slice, &["'", "\""]){
             // parse left_name name from quote-delimited string
             match consume_between_tokens(slice, &["'", "\""]){
-                Err(_)=> return Err(String::from("unfinished string")),
+                Err(_)=> return Err((slice, ErrorKind::UnfinishedString)),
                 Ok((remaining, str_content, offset)) => {
                     let (_, string) = unescape_to_string(str_content);
                     right_name = Some(string);
@@ -423,12 +543,12 @@ impl SequenceDiagramParser {
         }
         else{
             // parse name until whitespace or something that looks a special token or the message start
-            match consume_until_token_in_list(slice, &[" ", "\t", "+", "*", "!", ":"]) {
+            match consume_until_token_in_list(slice, &[" ", "\t", "+", "--", "*", "!", ":"]) {
                 Ok((remaining, parsed)) => {
                     slice=remaining;
                     right_name = Some(String::from(parsed));
                 },
-                Err(_) => {return Err(String::from("Bad format"));},
+                Err(_) => {return Err((slice, ErrorKind::Syntax(String::from("Bad format"))));},
             }
         }
 
@@ -437,9 +557,21 @@ impl SequenceDiagramParser {
             slice = new_slice;    
         }
 
-        // Then handle optional magic sortcuts
-        if let Ok((_remaining, _token)) = consume_token_in_list(slice, &["++", "**", "!!"]){
-            return Err(String::from("Not implemented yet"));
+        // Then handle optional magic sortcuts: '++' activates the target, '--' deactivates the origin,
+        // '**' creates the target and '!!' destroys it
+        let mut activate_target=false;
+        let mut deactivate_origin=false;
+        let mut create_target=false;
+        let mut destroy_target=false;
+        while let Ok((remaining, token)) = consume_token_in_list(slice, &["++", "--", "**", "!!"]){
+            match token{
+                "++" => activate_target=true,
+                "--" => deactivate_origin=true,
+                "**" => create_target=true,
+                _ => destroy_target=true,
+            }
+            let (new_slice, _) = consume_whitespaces(remaining);
+            slice = new_slice;
         }
 
         // Look for ':' separator, followed by arrow text
@@ -454,7 +586,7 @@ impl SequenceDiagramParser {
             if let Ok((_, _)) = consume_token_in_list(slice, &["'", "\""]){
                 // Read message text as a string
                 match consume_between_tokens(slice, &["'", "\""]){
-                    Err(_)=> return Err(String::from("unfinished string")),
+                    Err(_)=> return Err((slice, ErrorKind::UnfinishedString)),
                     Ok((remaining, str_content, _offset)) => {
                         let (_, string) = unescape_to_string(str_content);
                         arrow_text=Some(string);
@@ -471,13 +603,14 @@ impl SequenceDiagramParser {
 
         //Now create the connector
         let mut element:Element =Element::new("arrow");
+        element.source = self.source.clone();
         if let Some(text) = arrow_text{
             element.push(Rc::new(RefCell::new(Element::new_str("text", &text))));
         }
 
 
         if let Some(name)=left_name{
-            self.create_participant_if_needed(name.as_str());
+            let name = self.create_participant_if_needed(name.as_str());
             match arrow_direction{
                 ArrowDirection::Right | ArrowDirection::Bidirectional =>{
                     element.push_attribute("origin", &name);
@@ -487,7 +620,7 @@ impl SequenceDiagramParser {
         }
 
         if let Some(name)=right_name{
-            self.create_participant_if_needed(name.as_str());
+            let name = self.create_participant_if_needed(name.as_str());
             match arrow_direction{
                 ArrowDirection::Right | ArrowDirection::Bidirectional =>{
                     element.push_attribute("target", &name);
@@ -506,7 +639,7 @@ impl SequenceDiagramParser {
             match arrow_type{
                 ArrowType::Normal=>"normal",
                 ArrowType::Fine=>"fine",
-                ArrowType::Top=>"Top",
+                ArrowType::Top=>"top",
                 ArrowType::Bottom=>"bottom",
                 ArrowType::TopFine=>"top-fine",
                 ArrowType::BottomFine=>"bottom-fine",
@@ -514,15 +647,579 @@ impl SequenceDiagramParser {
                 ArrowType::BidirectionalFine=>"bidirectional-fine",
             }
         );
+        if let Some(border)=border{
+            element.push_attribute("border", border);
+        }
+        if let Some(id)=arrow_id{
+            element.push_attribute("id", &id);
+        }
+        self.number_message(&mut element);
+        // always on the target side, the parser only accepts it next to the head
+        if let Some(decor)=arrow_decor{
+            element.push_attribute("decor",
+                match decor{
+                    ArrowDecor::Round=>"round",
+                    ArrowDecor::Cross=>"cross",
+                }
+            );
+        }
+
+        let origin = element.get_attr("origin");
+        let target = element.get_attr("target");
+        for name in origin.iter().chain(target.iter()){
+            if self.destroyed.contains(name){
+                return Err((input, ErrorKind::Syntax(format!("'{}' is destroyed", name))));
+            }
+        }
+        // the participant is drawn where it's created, the message goes to it
+        if create_target{
+            match &target{
+                Some(target) => self.create(target).map_err(|kind| (input, kind))?,
+                None => return Err((input, ErrorKind::Syntax(String::from("'**' needs a target")))),
+            }
+        }
+        self.push_to_content(Rc::new(RefCell::new(element)));
+
+        for name in origin.iter().chain(target.iter()){
+            self.in_sequence.insert(name.clone());
+        }
+        if let (Some(origin), Some(target)) = (&origin, &target){
+            self.last_message = Some((origin.clone(), target.clone()));
+        }
+        // messages from or to the border only have one end
+        if activate_target{
+            match &target{
+                Some(target) => self.activate(target),
+                None => return Err((input, ErrorKind::Syntax(String::from("'++' needs a target")))),
+            }
+        }
+        if deactivate_origin{
+            match &origin{
+                Some(origin) => {self.deactivate(origin).map_err(|kind| (input, kind))?;},
+                None => return Err((input, ErrorKind::Syntax(String::from("'--' needs an origin")))),
+            }
+        }
+        if destroy_target{
+            match &target{
+                Some(target) => self.destroy(target).map_err(|kind| (input, kind))?,
+                None => return Err((input, ErrorKind::Syntax(String::from("'!!' needs a target")))),
+            }
+        }
+
+        return Ok(());
+    }
+
+    // Activations are stacked, a participant can be activated again while active:
+    // <activate participant="bob"/>
+    // <deactivate participant="bob"/>
+    fn activate(&mut self, alias: &str){
+        // remember who called, for 'return'
+        let caller = match &self.last_message {
+            Some((origin, target)) if target == alias => Some(origin.clone()),
+            _ => None,
+        };
+        self.in_sequence.insert(String::from(alias));
+        let mut element = Element::new("activate").attr("participant", alias);
+        element.source = self.source.clone();
+        self.push_to_content(Rc::new(RefCell::new(element)));
+        self.activations.push((String::from(alias), caller));
+    }
+
+    fn deactivate(&mut self, alias: &str) -> Result<Option<String>, ErrorKind>{
+        let caller = match self.activations.iter().rposition(|(a, _)| a == alias) {
+            Some(i) => self.activations.remove(i).1,
+            None => return Err(ErrorKind::Syntax(format!("'{}' is not active", alias))),
+        };
+        let mut element = Element::new("deactivate").attr("participant", alias);
+        element.source = self.source.clone();
+        self.push_to_content(Rc::new(RefCell::new(element)));
+        return Ok(caller);
+    }
+
+    // Created participants are marked in the header, and drawn where they are created:
+    // <participant alias="bob" created="true">...
+    // <create participant="bob"/>
+    fn create(&mut self, alias: &str) -> Result<(), ErrorKind>{
+        let participant = match self.participants_map.get(alias) {
+            Some(participant) => Rc::clone(participant),
+            None => return Err(ErrorKind::Internal(format!("unknown participant '{}'", alias))),
+        };
+        if participant.borrow().get_attr("created").is_some() {
+            return Err(ErrorKind::Syntax(format!("'{}' is already created", alias)));
+        }
+        if self.in_sequence.contains(alias) {
+            return Err(ErrorKind::Syntax(format!("'{}' can't be created, it's already in the sequence", alias)));
+        }
+        participant.borrow_mut().push_attribute("created", "true");
+
+        let mut element = Element::new("create").attr("participant", alias);
+        element.source = self.source.clone();
+        self.push_to_content(Rc::new(RefCell::new(element)));
+        return Ok(());
+    }
+
+    // <destroy participant="bob"/>, its lifeline ends there
+    fn destroy(&mut self, alias: &str) -> Result<(), ErrorKind>{
+        if !self.destroyed.insert(String::from(alias)) {
+            return Err(ErrorKind::Syntax(format!("'{}' is already destroyed", alias)));
+        }
+        // and so do its activations
+        self.activations.retain(|(a, _)| a != alias);
+
+        let mut element = Element::new("destroy").attr("participant", alias);
+        element.source = self.source.clone();
+        self.push_to_content(Rc::new(RefCell::new(element)));
+        return Ok(());
+    }
+
+    // 'create [participant type] name' and 'destroy name'
+    fn add_lifecycle<'a>(&mut self, input: &'a str, token: &str) -> Result<(), ParseError<'a>>{
+        let mut slice = input.trim();
+        let mut participant_type = "participant";
+        if token == "create" {
+            if let Ok((remaining, participant_token)) = consume_keyword_in_list(slice, &RESERVED_TOKENS_HEADER[..7]) {
+                participant_type = participant_token;
+                slice = remaining.trim_start();
+            }
+        }
+        if slice.len() == 0 {
+            return Err((input, ErrorKind::Syntax(String::from("expecting participant name"))));
+        }
+        let name = match Self::consume_name(slice)? {
+            (remaining, name) if remaining.trim().len() == 0 => match name.borrow().get("text()") {
+                Some(QueryResult::Text(name)) => name,
+                _ => return Err((slice, ErrorKind::Syntax(String::from("expecting participant name")))),
+            },
+            (remaining, _) => return Err((remaining.trim_start(), ErrorKind::UnexpectedToken(String::from(remaining.trim())))),
+        };
+
+        if token == "create" {
+            let alias = self.create_participant_with_type(&name, participant_type);
+            self.create(&alias).map_err(|kind| (input, kind))?;
+        }
+        else {
+            let alias = self.resolve_participant(&name);
+            if !self.participants_map.contains_key(&alias) {
+                return Err((slice, ErrorKind::Syntax(format!("unknown participant '{}'", name))));
+            }
+            self.destroy(&alias).map_err(|kind| (input, kind))?;
+        }
+        return Ok(());
+    }
+
+    // Notes are attached to one participant, or span several of them with 'over a,b':
+    // <note shape="note|rnote|hnote" position="left|right|over" participant="a" to="b">
+    //     <format format="paragraph">...</format>
+    // </note>
+    // One paragraph per line of text.
+    // The text is on the same line after ':', or on the next lines until 'end note'
+    fn add_note<'a>(&mut self, input: &SliceWithContext<'a>, rest: &'a str, shape: &str) -> Result<bool, FoggyError>{
+        let mut slice = rest.trim_start();
+        let position = match consume_keyword_in_list(slice, &["left of", "right of", "left", "right", "over"]) {
+            Ok((remaining, token)) => {
+                slice = remaining.trim_start();
+                match token {
+                    "left of"|"left" => "left",
+                    "right of"|"right" => "right",
+                    _ => "over",
+                }
+            },
+            Err(_) => return Err(input.error_at(slice, ErrorKind::Syntax(String::from("expecting 'left', 'right' or 'over'")))),
+        };
+
+        let mut names = vec![];
+        loop {
+            let (remaining, name) = Self::consume_participant_reference(slice)
+                .map_err(|(rest, kind)| input.error_at(rest, kind))?;
+            names.push(self.create_participant_if_needed(&name));
+            slice = remaining.trim_start();
+            match consume_token_in_list(slice, &[","]) {
+                Ok((remaining, _)) if position == "over" => slice = remaining.trim_start(),
+                _ => break,
+            }
+        }
+        if names.len() > 2 {
+            return Err(input.error_at(rest, ErrorKind::Syntax(String::from("a note spans 2 participants at most"))));
+        }
+
+        let mut note = Element::new("note")
+            .attr("shape", shape)
+            .attr("position", position)
+            .attr("participant", &names[0]);
+        if names.len() == 2 {
+            note.push_attribute("to", &names[1]);
+        }
+        note.source = self.source.clone();
+        for name in names {
+            self.in_sequence.insert(name);
+        }
+        let note = Rc::new(RefCell::new(note));
+        let mut markdown = MarkdownParser::new();
+
+        if let Ok((remaining, _)) = consume_token_in_list(slice, &[":"]) {
+            Self::push_note_line(&mut markdown, &input.tail(remaining.trim_start()))?;
+            self.note = Some((note, markdown));
+            self.end_note();
+            return Ok(false);
+        }
+        if slice.len() > 0 {
+            return Err(input.error_at(slice, ErrorKind::UnexpectedToken(String::from(slice.trim()))));
+        }
+        self.note = Some((note, markdown));
+        return Ok(true);
+    }
+
+    fn push_note_line(markdown: &mut MarkdownParser, input: &SliceWithContext<'_>) -> Result<(), FoggyError>{
+        let mut line = input.tail(input.slice);
+        markdown.step(&mut line)?;
+        // each line is a paragraph
+        let mut empty = input.tail(&input.slice[input.slice.len()..]);
+        markdown.step(&mut empty)?;
+        return Ok(());
+    }
+
+    fn end_note(&mut self){
+        if let Some((note, mut markdown)) = self.note.take() {
+            let (elements, _) = markdown.flush();
+            for e in elements {
+                note.borrow_mut().push(e);
+            }
+            self.push_to_content(note);
+        }
+    }
+
+    // A participant name, maybe quoted, followed by a space, ',' or ':'
+    fn consume_participant_reference(input: &str) -> Result<(&str, String), ParseError<'_>>{
+        if starts_with_token(input, &["'", "\""]) {
+            return match consume_between_tokens(input, &["'", "\""]) {
+                Err(_) => Err((input, ErrorKind::UnfinishedString)),
+                Ok((remaining, str_content, _offset)) => Ok((remaining, unescape_to_string(str_content).1)),
+            };
+        }
+        let (remaining, name) = consume_until_token_in_list(input, &[" ", "\t", ",", ":"]).unwrap();
+        if name.len() == 0 {
+            return Err((input, ErrorKind::Syntax(String::from("expecting participant name"))));
+        }
+        return Ok((remaining, String::from(name)));
+    }
+
+    // Vertical layout of the sequence:
+    // '...' or '... text ...' is a delay: <delay><text>text</text></delay>
+    // '|||' is some space, or '||45||' for 45 units: <space height="45"/>
+    // '== text ==' separates sections: <separator><text>text</text></separator>
+    fn add_vertical_layout<'a>(&mut self, input: &'a str, token: &str) -> Result<(), ParseError<'a>>{
+        let slice = input.trim();
+        let mut element = match token {
+            "..." => Element::new("delay"),
+            "==" => Element::new("separator"),
+            _ => Element::new("space"),
+        };
+        element.source = self.source.clone();
+
+        match token {
+            "..."|"==" => {
+                let text = if slice.len() == 0 && token == "..." {
+                    ""
+                }
+                else if slice.ends_with(token) {
+                    slice[..slice.len() - token.len()].trim()
+                }
+                else {
+                    return Err((&input[input.len()..], ErrorKind::Syntax(format!("expecting '{}' at the end of the line", token))));
+                };
+                if text.len() > 0 {
+                    let (_, text) = unescape_to_string(text);
+                    element.push(Rc::new(RefCell::new(Element::new_str("text", &text))));
+                }
+            },
+            "||" => {
+                let height = match slice.find("||") {
+                    Some(end) if slice[end + 2..].trim().len() == 0 => slice[..end].trim(),
+                    _ => return Err((input, ErrorKind::Syntax(String::from("expecting '||height||'")))),
+                };
+                match height.parse::<u32>() {
+                    Ok(_) => element.push_attribute("height", height),
+                    Err(_) => return Err((input, ErrorKind::Syntax(format!("invalid height '{}'", height)))),
+                }
+            },
+            _ => if slice.len() > 0 {
+                return Err((input.trim_start(), ErrorKind::UnexpectedToken(String::from(slice))));
+            },
+        }
+        self.push_to_content(Rc::new(RefCell::new(element)));
+        return Ok(());
+    }
+
+    // 'activate name' and 'deactivate name', or the last activated participant
+    fn add_activation<'a>(&mut self, input: &'a str, token: &str) -> Result<(), ParseError<'a>>{
+        let slice = input.trim();
+        let name = if slice.len() == 0 {
+            None
+        }
+        else {
+            match Self::consume_name(slice)? {
+                (remaining, name) if remaining.trim().len() == 0 => name.borrow().get("text()"),
+                (remaining, _) => return Err((remaining.trim_start(), ErrorKind::UnexpectedToken(String::from(remaining.trim())))),
+            }
+        };
+        let alias = match (name, token) {
+            (Some(QueryResult::Text(name)), "activate") => self.create_participant_if_needed(&name),
+            (Some(QueryResult::Text(name)), _) => self.resolve_participant(&name),
+            (_, "activate") => return Err((input, ErrorKind::Syntax(String::from("expecting participant name")))),
+            (_, _) => match self.activations.last() {
+                Some((alias, _)) => alias.clone(),
+                None => return Err((input, ErrorKind::Syntax(String::from("no active participant")))),
+            },
+        };
+        if token == "activate" {
+            self.activate(&alias);
+        }
+        else {
+            self.deactivate(&alias).map_err(|kind| (input, kind))?;
+        }
+        return Ok(());
+    }
+
+    // 'return text' is a dotted message from the last activated participant to its caller, then a deactivation
+    fn add_return<'a>(&mut self, input: &'a str, token_slice: &'a str) -> Result<(), ParseError<'a>>{
+        let (alias, caller) = match self.activations.last() {
+            Some((alias, Some(caller))) => (alias.clone(), caller.clone()),
+            Some((alias, None)) => return Err((token_slice, ErrorKind::Syntax(format!("don't know where '{}' returns to", alias)))),
+            None => return Err((token_slice, ErrorKind::Syntax(String::from("'return' without activation")))),
+        };
+
+        let mut element = Element::new("arrow")
+            .attr("origin", &alias)
+            .attr("target", &caller)
+            .attr("line-style", "dotted")
+            .attr("arrow-style", "normal");
+        element.source = self.source.clone();
+        let (_, text) = unescape_to_string(input.trim());
+        if text.len() > 0 {
+            element.push(Rc::new(RefCell::new(Element::new_str("text", &text))));
+        }
+        self.number_message(&mut element);
+        self.push_to_content(Rc::new(RefCell::new(element)));
+        self.last_message = Some((alias.clone(), caller));
+        self.deactivate(&alias).map_err(|kind| (token_slice, kind))?;
+        return Ok(());
+    }
+
+    // '{id}': returns the rest of the line and the id
+    fn consume_id(input: &str) -> Result<(&str, &str), ParseError<'_>>{
+        let slice = &input[1..];
+        match slice.find('}') {
+            Some(end) if slice[..end].trim().len() > 0 => Ok((&slice[end + 1..], slice[..end].trim())),
+            Some(_) => Err((input, ErrorKind::Syntax(String::from("empty {id}")))),
+            None => Err((input, ErrorKind::Syntax(String::from("unfinished {id}")))),
+        }
+    }
+
+    // Duration between two messages with an id, '{req} <-> {resp} : 200 ms max':
+    // <constraint start="req" end="resp"><text>200 ms max</text></constraint>
+    fn add_constraint<'a>(&mut self, start: &str, input: &'a str) -> Result<(), ParseError<'a>>{
+        let slice = input.trim_start();
+        if !starts_with_token(slice, &["{"]) {
+            return Err((slice, ErrorKind::Syntax(String::from("expecting '{id}' after '<->'"))));
+        }
+        let (remaining, end) = Self::consume_id(slice)?;
+        let mut slice = remaining.trim_start();
+        let end = String::from(end);
+        for id in &[start, end.as_str()]{
+            if !self.message_ids.contains(*id){
+                return Err((input, ErrorKind::Syntax(format!("unknown message id '{}'", id))));
+            }
+        }
 
-        self.sequence.push(Rc::new(RefCell::new(element)));
+        let mut element = Element::new("constraint")
+            .attr("start", start)
+            .attr("end", &end);
+        element.source = self.source.clone();
+        if slice.len() > 0 {
+            match consume_token_in_list(slice, &[":"]) {
+                Ok((remaining, _)) => slice = remaining.trim(),
+                Err(_) => return Err((slice, ErrorKind::UnexpectedToken(String::from(slice.trim())))),
+            }
+            let (_, text) = unescape_to_string(slice);
+            if text.len() > 0 {
+                element.push(Rc::new(RefCell::new(Element::new_str("text", &text))));
+            }
+        }
+        self.push_to_content(Rc::new(RefCell::new(element)));
+        return Ok(());
+    }
 
+    // autonumber [start] [step] ["format"]
+    // autonumber stop
+    // autonumber resume [step] ["format"]
+    // In the format, a run of '0' is the number padded with zeros, '#' is the number as is,
+    // and <b>...</b> makes it bold. The number is bold by default
+    fn set_autonumber<'a>(&mut self, input: &'a str) -> Result<(), ParseError<'a>>{
+        let mut slice = input.trim();
+        let resume = match consume_keyword_in_list(slice, &["stop", "resume"]) {
+            Ok((remaining, "stop")) => {
+                if remaining.trim().len() > 0 {
+                    return Err((remaining.trim_start(), ErrorKind::UnexpectedToken(String::from(remaining.trim()))));
+                }
+                return match self.autonumber.as_mut() {
+                    Some(autonumber) => {
+                        autonumber.running = false;
+                        Ok(())
+                    },
+                    None => Err((slice, ErrorKind::Syntax(String::from("'autonumber stop' without autonumber")))),
+                };
+            },
+            Ok((remaining, _)) => {
+                if self.autonumber.is_none() {
+                    return Err((slice, ErrorKind::Syntax(String::from("'autonumber resume' without autonumber"))));
+                }
+                slice = remaining.trim_start();
+                true
+            },
+            Err(_) => false,
+        };
+
+        // numbers, the start is only for a new numbering
+        let mut numbers: Vec<u32> = vec![];
+        while slice.len() > 0 && !starts_with_token(slice, &["'", "\""]) {
+            let (remaining, number) = consume_until_whitespace(slice).unwrap();
+            match number.parse::<u32>() {
+                Ok(n) if numbers.len() < if resume {1} else {2} => numbers.push(n),
+                _ => return Err((slice, ErrorKind::Syntax(format!("invalid autonumber '{}'", number)))),
+            }
+            slice = remaining.trim_start();
+        }
+        let format = if slice.len() > 0 {
+            match consume_between_tokens(slice, &["'", "\""]){
+                Err(_)=> return Err((slice, ErrorKind::UnfinishedString)),
+                Ok((remaining, str_content, _offset)) => {
+                    if remaining.trim().len() > 0 {
+                        return Err((remaining.trim_start(), ErrorKind::UnexpectedToken(String::from(remaining.trim()))));
+                    }
+                    Some(unescape_to_string(str_content).1)
+                },
+            }
+        }
+        else {
+            None
+        };
+
+        match self.autonumber.as_mut() {
+            Some(autonumber) if resume => {
+                if let Some(step) = numbers.first() {
+                    autonumber.step = *step;
+                }
+                if let Some(format) = format {
+                    autonumber.format = format;
+                }
+                autonumber.running = true;
+            },
+            _ => self.autonumber = Some(Autonumber {
+                next: numbers.get(0).cloned().unwrap_or(1),
+                step: numbers.get(1).cloned().unwrap_or(1),
+                format: format.unwrap_or_else(|| String::from("<b>#</b>")),
+                running: true,
+            }),
+        }
         return Ok(());
     }
 
+    // number="12" number-style="bold", on messages while autonumber is running
+    fn number_message(&mut self, element: &mut Element){
+        let autonumber = match self.autonumber.as_mut() {
+            Some(autonumber) if autonumber.running => autonumber,
+            _ => return,
+        };
+        let (number, bold) = format_number(autonumber.next, &autonumber.format);
+        element.push_attribute("number", &number);
+        element.push_attribute("number-style", if bold {"bold"} else {"normal"});
+        autonumber.next += autonumber.step;
+    }
 
+    // Fragments are containers for content, with one section per alternative:
+    // <fragment type="alt">
+    //     <section>
+    //         <label>success</label>
+    //         <arrow .../>
+    //     </section>
+    //     <section>
+    //         <label>failure</label>
+    //         <arrow .../>
+    //     </section>
+    // </fragment>
+    // The label is optional
+    fn new_section<'a>(&self, input: &'a str) -> Result<Element, ParseError<'a>>{
+        let mut section = Element::new("section");
+        section.source = self.source.clone();
+
+        // same as arrow text, the label can be a string
+        let slice = input.trim();
+        let label = if starts_with_token(slice, &["'", "\""]) {
+            match consume_between_tokens(slice, &["'", "\""]){
+                Err(_)=> return Err((slice, ErrorKind::UnfinishedString)),
+                Ok((_, str_content, _offset)) => unescape_to_string(str_content).1,
+            }
+        }
+        else {
+            unescape_to_string(slice).1
+        };
+        if label.len() > 0 {
+            section.push(Rc::new(RefCell::new(Element::new_str("label", &label))));
+        }
+        return Ok(section);
+    }
+
+    fn open_fragment<'a>(&mut self, input: &'a str, fragment_type: &str) -> Result<(), ParseError<'a>>{
+        let mut fragment = Element::new("fragment")
+            .attr("type", fragment_type);
+        fragment.source = self.source.clone();
+
+        let section = Rc::new(RefCell::new(self.new_section(input)?));
+        fragment.push(Rc::clone(&section));
+
+        let fragment = Rc::new(RefCell::new(fragment));
+        self.push_to_content(Rc::clone(&fragment));
+        self.open_fragments.push((fragment, section));
+        return Ok(());
+    }
+
+    fn add_else<'a>(&mut self, input: &'a str, token_slice: &'a str) -> Result<(), ParseError<'a>>{
+        let fragment = match self.open_fragments.last() {
+            Some((fragment, _)) => Rc::clone(fragment),
+            None => return Err((token_slice, ErrorKind::Syntax(String::from("'else' without 'alt' or 'par'")))),
+        };
+        let fragment_type = fragment.borrow().get_attr("type").unwrap_or_default();
+        if fragment_type != "alt" && fragment_type != "par" {
+            return Err((token_slice, ErrorKind::Syntax(format!("'else' is not allowed in '{}'", fragment_type))));
+        }
+
+        let section = Rc::new(RefCell::new(self.new_section(input)?));
+        fragment.borrow_mut().push(Rc::clone(&section));
+        self.open_fragments.pop();
+        self.open_fragments.push((fragment, section));
+        return Ok(());
+    }
+
+    fn end_fragment<'a>(&mut self, input: &'a str, token_slice: &'a str) -> Result<(), ParseError<'a>>{
+        if input.trim().len() > 0 {
+            return Err((input, ErrorKind::UnexpectedToken(String::from(input.trim()))));
+        }
+        if self.open_fragments.pop().is_none() {
+            return Err((token_slice, ErrorKind::UnexpectedToken(String::from("end"))));
+        }
+        return Ok(());
+    }
+
+    fn push_to_content(&mut self, element:Rcc<Element>){
+        match self.open_fragments.last() {
+            Some((_, section)) => section.borrow_mut().push(element),
+            None => self.sequence.push(element),
+        }
+    }
 
-    fn consume_arrow(input:&str)->Result<(&str, ArrowDirection, ArrowLineType, ArrowType, Option<ArrowDecor>), String>{
+
+
+    fn consume_arrow(input:&str)->Result<(&str, ArrowDirection, ArrowLineType, ArrowType, Option<ArrowDecor>), ParseError<'_>>{
         //TODO find a way to define this map statically and not for each call
         let  map: std::collections::HashMap<&str, (ArrowDirection, ArrowLineType, ArrowType)>= 
             hashmap![
@@ -533,7 +1230,7 @@ impl SequenceDiagramParser {
                 "->>"    => (ArrowDirection::Right, ArrowLineType::Normal, ArrowType::Fine),
                 "<<-"    => (ArrowDirection::Left, ArrowLineType::Normal, ArrowType::Fine),
                 "-->>"   => (ArrowDirection::Right, ArrowLineType::Dotted, ArrowType::Fine),
-                "<<--"   => (ArrowDirection::Left, ArrowLineType::Dotted, ArrowType::Normal),
+                "<<--"   => (ArrowDirection::Left, ArrowLineType::Dotted, ArrowType::Fine),
                 "-/"     => (ArrowDirection::Right, ArrowLineType::Normal, ArrowType::Bottom),
                 "\\-"    => (ArrowDirection::Left, ArrowLineType::Normal, ArrowType::Bottom),
                 "--/"    => (ArrowDirection::Right, ArrowLineType::Dotted, ArrowType::Bottom),
@@ -557,7 +1254,7 @@ impl SequenceDiagramParser {
             ];
 
 
-        let valid_tokens=[
+        let mut valid_tokens=[
             "->","<-","-->","<--",
             "->>","<<-","-->>","<<--",
             "-/","\\-","--/","\\--",
@@ -566,6 +1263,8 @@ impl SequenceDiagramParser {
             "-\\\\", "//-", "--\\\\", "//--",
             "<->","<<->>","<-->","<<-->>",
         ];
+        // the first match wins: '-->>' must not be read as '-->' followed by '>'
+        valid_tokens.sort_by(|a, b| b.len().cmp(&a.len()));
 
         let mut slice = input;
 
@@ -591,11 +1290,11 @@ impl SequenceDiagramParser {
                     arrow_line_type = *line_type;
                     arrow_type= *a_type;
                     if arrow_direction == ArrowDirection::Bidirectional && arrow_decor != None {
-                        return Err(String::from("Invalid arrow : bidirectional arrows can't have any decoration"));
+                        return Err((slice, ErrorKind::Syntax(String::from("Invalid arrow : bidirectional arrows can't have any decoration"))));
                     }
                     // Previously set decoration is only valid if the arrow goes left
                     if arrow_direction == ArrowDirection::Right && arrow_decor != None {
-                        return Err(String::from("Invalid arrow : decoration before right arrow must be on right ride"));
+                        return Err((slice, ErrorKind::Syntax(String::from("Invalid arrow : decoration before right arrow must be on right ride"))));
                     }
                     slice = remaining;
                 }
@@ -603,7 +1302,7 @@ impl SequenceDiagramParser {
             }
         }
         else{
-            return Err(String::from("Invalid arrow"));
+            return Err((slice, ErrorKind::Syntax(String::from("Invalid arrow"))));
         }
 
         // If arrow goes right, look for optional decoration
@@ -620,15 +1319,42 @@ impl SequenceDiagramParser {
 
 
 
-    fn create_participant_if_needed(&mut self, name: &str){
-        if !self.participants_map.contains_key(&String::from(name)){
+    // Alias of a participant declared on the current line
+    fn qualify(&self, alias: &str) -> String {
+        if self.namespace.len() == 0 {
+            return String::from(alias);
+        }
+        return format!("{}.{}", self.namespace, alias);
+    }
+
+    // Alias of a participant referenced on the current line, as written:
+    // first look in the namespace of the line, then for the name as it is (lib.alice, or a top level name).
+    // Unknown participants are created in the namespace of the line
+    fn resolve_participant(&self, name: &str) -> String {
+        let qualified = self.qualify(name);
+        if self.participants_map.contains_key(&qualified) || !self.participants_map.contains_key(name) {
+            return qualified;
+        }
+        return String::from(name);
+    }
+
+    // Returns the alias of the participant
+    fn create_participant_if_needed(&mut self, name: &str) -> String{
+        return self.create_participant_with_type(name, "participant");
+    }
+
+    fn create_participant_with_type(&mut self, name: &str, participant_type: &str) -> String{
+        let alias = self.resolve_participant(name);
+        if !self.participants_map.contains_key(&alias){
 
             // Build participant element ans push it to header
-            let header_element = Element::new("participant")
-                .attr("alias", name)
+            let mut header_element = Element::new(participant_type)
+                .attr("alias", &alias)
                 .child(Element::new_str("name", name));
+            header_element.source = self.source.clone();
             self.push_to_header(Rc::new(RefCell::new(header_element)));
         }
+        return alias;
     }
 
     fn push_to_header(&mut self, element:Rc<RefCell<Element>>){
@@ -639,17 +1365,15 @@ impl SequenceDiagramParser {
 
         //keep track of participants for future queries
         let mut name= String::new();
-        let mut participant=true;
+        let participant;
         {
             let elt=element.borrow();
             for (k,v) in elt.get_attributes(){
                 if k == "alias"{
                     name.push_str(v);
                 }
-                if k == "type" && v == "box"{
-                    participant = false;
-                }
             }
+            participant = elt.get_tag() != "box";
         }
         if participant{
             self.participants_map.insert(name, Rc::clone(&element));
@@ -676,12 +1400,28 @@ impl Parser for SequenceDiagramParser{
     fn step<'b>(
         &mut self,
         input: &'b mut SliceWithContext<'b>,
-    ) -> Result<ParserResult<'b>, (&'b SliceWithContext<'b>, String)> {
+    ) -> Result<ParserResult<'b>, FoggyError> {
         let mut slice: &str = input.slice;
 
         // Shall not happen
         if self.collec == None {
-            return Err((input, String::from("runtime error, collector shall not be none")));
+            return Err(input.error_at(slice, ErrorKind::Internal(String::from("collector shall not be none"))));
+        }
+        self.namespace = Rc::clone(&input.namespace);
+
+        // lines of a multi-line note, until 'end note'
+        if self.note.is_some() {
+            let trimmed = slice.trim();
+            if ["end note", "end rnote", "end hnote", "endnote", "endrnote", "endhnote"].contains(&trimmed) {
+                self.end_note();
+                input.slice=&input.slice[input.slice.len()..];
+                return Ok(ParserResult::Partial(input));
+            }
+            if let Some((_, markdown)) = self.note.as_mut() {
+                Self::push_note_line(markdown, &input.tail(slice.trim_start()))?;
+            }
+            input.slice=&input.slice[input.slice.len()..];
+            return Ok(ParserResult::Busy);
         }
 
         {
@@ -689,24 +1429,32 @@ impl Parser for SequenceDiagramParser{
            let (trimmed_slice, _) = consume_whitespaces(slice);
            slice = trimmed_slice;
        }
+        self.source = Some(input.span_at(slice));
+
+        // empty lines are allowed anywhere and don't end the header
+        if slice.len() == 0 {
+            input.slice = slice;
+            return Ok(ParserResult::Partial(input));
+        }
 
         // header line starts with keyword
         if self.state == SequenceDiagramParserState::Header{
             // Check for tokens that are always at the start of line in the header
-            if  let Ok((new_slice, token)) = consume_token_in_list(slice, &RESERVED_TOKENS_HEADER) {
-            	input.slice = new_slice;
-                match token {
+            if  let Ok((new_slice, token)) = consume_keyword_in_list(slice, &RESERVED_TOKENS_HEADER) {
+                let result = match token {
                     "participant"|"actor"|"boundary"|"control"|"entity"|"database"|"collections" 
-                        => {self.add_participant(input.slice, token);},
-                    "box" => {self.add_box(input.slice);},
-                    "end box" => {self.end_box();},
-                    "title" => return Err((input, String::from("runtime error, invalid condition"))),
-                    "hide" => return Err((input, String::from("runtime error, invalid condition"))),
-                    "show" => return Err((input, String::from("runtime error, invalid condition"))),
-                    _ => return Err((input, String::from("runtime error, invalid condition"))),
+                        => self.add_participant(new_slice, token),
+                    "box" => self.add_box(new_slice),
+                    "end box" => self.end_box(slice),
+                    "title"|"hide"|"show" => self.add_header_option(new_slice, token),
+                    "autonumber" => self.set_autonumber(new_slice),
+                    _ => Err((slice, ErrorKind::NotImplemented(format!("'{}'", token)))),
+                };
+                if let Err((rest, kind)) = result {
+                    return Err(input.error_at(rest, kind));
                 }
                 //TODO make it better
-                input.slice=&input.slice[..0];
+                input.slice=&input.slice[input.slice.len()..];
                 return Ok(ParserResult::Partial(input));
 
             }
@@ -717,14 +1465,33 @@ impl Parser for SequenceDiagramParser{
                 self.state = SequenceDiagramParserState::Content;
             }
         }
-        // content line starts with keyword
-        if  let Ok((slice, _token)) = consume_token_in_list(slice, &RESERVED_TOKENS_SEQUENCE) {
-            return Err((input, String::from("not implemented")));
+        // notes may take the next lines, and their text is parsed by an other parser
+        if let Ok((new_slice, token)) = consume_keyword_in_list(slice, &["note", "rnote", "hnote"]) {
+            let busy = self.add_note(input, new_slice, token)?;
+            input.slice=&input.slice[input.slice.len()..];
+            return Ok(if busy {ParserResult::Busy} else {ParserResult::Partial(input)});
         }
-        else{
-            self.add_message(slice);
+
+        // content line starts with keyword
+        let result = match consume_keyword_in_list(slice, &RESERVED_TOKENS_SEQUENCE) {
+            Ok((new_slice, token)) => match token {
+                "alt"|"loop"|"opt"|"par"|"group" => self.open_fragment(new_slice, token),
+                "else" => self.add_else(new_slice, slice),
+                "end" => self.end_fragment(new_slice, slice),
+                "activate"|"deactivate" => self.add_activation(new_slice, token),
+                "return" => self.add_return(new_slice, slice),
+                "create"|"destroy" => self.add_lifecycle(new_slice, token),
+                "..."|"|||"|"||"|"==" => self.add_vertical_layout(new_slice, token),
+                "autonumber" => self.set_autonumber(new_slice),
+                _ => Err((slice, ErrorKind::NotImplemented(format!("'{}'", token)))),
+            },
+            Err(_) => self.add_message(slice),
+        };
+        if let Err((rest, kind)) = result {
+            return Err(input.error_at(rest, kind));
         }
- 
+
+        input.slice=&input.slice[input.slice.len()..];
         return Ok(ParserResult::Partial(input));
     }
 
@@ -732,6 +1499,13 @@ impl Parser for SequenceDiagramParser{
         let mut header_element = Element::new("sequencediagram:header");
         let mut content_element = Element::new("sequencediagram:content");
 
+        for (name, value) in &self.options {
+            header_element.push_attribute(name, value);
+        }
+        if let Some(title) = self.title.take() {
+            header_element.push(Rc::new(RefCell::new(title)));
+        }
+
         for elt in &self.header {
             header_element.push(Rc::clone(&elt));
         }
@@ -741,11 +1515,64 @@ impl Parser for SequenceDiagramParser{
         }  
         return (vec![Rc::new(RefCell::new(header_element)), Rc::new(RefCell::new(content_element))], vec![]);
     }
+
+    fn end(&mut self) -> Result<(), FoggyError> {
+        // report the note or the innermost fragment, the outer ones may be fine
+        let unclosed = match (&self.note, self.open_fragments.last()) {
+            (Some((note, _)), _) | (None, Some((note, _))) => Some(note),
+            (None, None) => None,
+        };
+        if let Some(block) = unclosed {
+            let kind = ErrorKind::UnclosedBlock;
+            return match &block.borrow().source {
+                Some(s) => Err(FoggyError::at(kind, &s.file_name, s.line, s.column)),
+                None => Err(FoggyError::new(kind)),
+            };
+        }
+        return Ok(());
+    }
 }
 
 
 
 
+// The number written with an autonumber format, and whether it's bold.
+// Tags are removed, the number is added at the end if the format has no '0' or '#'
+fn format_number(number: u32, format: &str) -> (String, bool){
+    let mut result = String::new();
+    let mut bold = false;
+    let mut written = false;
+    let mut chars = format.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '<' => {
+                let tag: String = chars.by_ref().take_while(|c| *c != '>').collect();
+                if tag.trim() == "b" {
+                    bold = true;
+                }
+            },
+            '0' | '#' => {
+                let mut width = 1;
+                while chars.peek() == Some(&c) {
+                    chars.next();
+                    width += 1;
+                }
+                match c {
+                    '0' => result.push_str(&format!("{:0width$}", number, width = width)),
+                    _ => result.push_str(&number.to_string()),
+                }
+                written = true;
+            },
+            _ => result.push(c),
+        }
+    }
+    if !written {
+        result.push_str(&number.to_string());
+    }
+    return (result, bold);
+}
+
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -1041,5 +1868,641 @@ mod tests {
 
     }
 
+    #[test]
+    fn test_sequenceparser_errors() {
+        let mut parser = SequenceDiagramParser::new();
+        {
+            let mut slice = SliceWithContext::new_for_tests(&"participant \"bob");
+            let error = parser.step(&mut slice).err().unwrap();
+            assert_eq!(error.kind, ErrorKind::UnfinishedString);
+            assert_eq!(error.column, 13);
+        }
+        {
+            let mut slice = SliceWithContext::new_for_tests(&"participant bob is bobby");
+            let error = parser.step(&mut slice).err().unwrap();
+            assert_eq!(error.kind, ErrorKind::UnexpectedToken(String::from("is")));
+            assert_eq!(error.column, 17);
+        }
+        {
+            let mut slice = SliceWithContext::new_for_tests(&"  alice => bob");
+            let error = parser.step(&mut slice).err().unwrap();
+            assert_eq!(error.kind, ErrorKind::Syntax(String::from("Invalid arrow")));
+            assert_eq!(error.column, 9);
+        }
+    }
+
     // And now for some external tests
+
+    #[test]
+    fn test_sequenceparser_namespaces() {
+        let mut parser = SequenceDiagramParser::new();
+        let lib = Rc::new(String::from("lib"));
+        let top = Rc::new(String::new());
+
+        let lines = [
+            // imported from lib
+            (&lib, "participant alice"),
+            (&lib, "participant \"Long name\" as long"),
+            // top level, same name without clash
+            (&top, "participant alice"),
+            (&lib, "alice -> bob"),
+            (&top, "alice -> lib.alice"),
+            (&top, "lib.long -> bob"),
+        ];
+        for (namespace, line) in lines.iter() {
+            let mut slice = SliceWithContext::new_for_tests(line);
+            slice.namespace = Rc::clone(namespace);
+            assert!(parser.step(&mut slice).is_ok());
+        }
+
+        let (elements, _documents) = parser.flush();
+        let children = |elt: &Rcc<Element>| match &elt.borrow().content {
+            ElementContent::Tree(c) => c.children.clone(),
+            _ => vec![],
+        };
+        let name = |elt: &Rcc<Element>| match elt.borrow().get("name/text()") {
+            Some(QueryResult::Text(n)) => n,
+            _ => String::new(),
+        };
+        let participants: Vec<(String, String)> = children(&elements[0]).iter()
+            .map(|p| (p.borrow().get_attr("alias").unwrap(), name(p)))
+            .collect();
+        let participant = |alias: &str, name: &str| (String::from(alias), String::from(name));
+        assert_eq!(participants, vec![
+            participant("lib.alice", "alice"),
+            participant("lib.long", "Long name"),
+            participant("alice", "alice"),
+            participant("lib.bob", "bob"),
+            participant("bob", "bob"),
+        ]);
+
+        let arrows: Vec<(String, String)> = children(&elements[1]).iter()
+            .map(|a| (a.borrow().get_attr("origin").unwrap(), a.borrow().get_attr("target").unwrap()))
+            .collect();
+        assert_eq!(arrows, vec![
+            (String::from("lib.alice"), String::from("lib.bob")),
+            (String::from("alice"), String::from("lib.alice")),
+            (String::from("lib.long"), String::from("bob")),
+        ]);
+    }
+
+    fn parse_lines(lines: &[&'static str]) -> Result<Vec<Rcc<Element>>, FoggyError> {
+        let mut parser = SequenceDiagramParser::new();
+        for line in lines {
+            let mut slice = SliceWithContext::new_for_tests(line);
+            parser.step(&mut slice)?;
+        }
+        parser.end()?;
+        let (elements, _documents) = parser.flush();
+        return Ok(elements);
+    }
+
+    #[test]
+    fn test_sequenceparser_fragments() {
+        let elements = parse_lines(&[
+            "alt success",
+            "  alice -> bob",
+            "  loop",
+            "    bob -> alice",
+            "  end",
+            "else \"fail\"",
+            "end",
+            "endpoint -> alice",
+        ]).unwrap();
+
+        let arrow = |origin: &str, target: &str| Element::new("arrow")
+            .attr("origin", origin)
+            .attr("target", target)
+            .attr("line-style", "normal")
+            .attr("arrow-style", "normal");
+        let expected = rcc(Element::new("sequencediagram:content")
+            .child(Element::new("fragment")
+                .attr("type", "alt")
+                .child(Element::new("section")
+                    .child(Element::new_str("label", "success"))
+                    .child(arrow("alice", "bob"))
+                    .child(Element::new("fragment")
+                        .attr("type", "loop")
+                        .child(Element::new("section")
+                            .child(arrow("bob", "alice"))
+                        )
+                    )
+                )
+                .child(Element::new("section")
+                    .child(Element::new_str("label", "fail"))
+                )
+            )
+            .child(arrow("endpoint", "alice"))
+        );
+        assert_eq!(elements[1], expected);
+    }
+
+    #[test]
+    fn test_sequenceparser_fragment_errors() {
+        let error = |lines: &[&'static str]| parse_lines(lines).unwrap_err().to_string();
+
+        assert_eq!(error(&["alice -> bob", "end"]), "file.txt: unexpected token 'end'");
+        assert_eq!(error(&["alice -> bob", "else"]), "file.txt: 'else' without 'alt' or 'par'");
+        assert_eq!(error(&["loop", "else"]), "file.txt: 'else' is not allowed in 'loop'");
+        assert_eq!(error(&["opt", "end now"]), "file.txt: unexpected token 'now'");
+        // the innermost fragment that is not closed
+        assert_eq!(error(&["group", "  par", "  end", "  opt"]), "file.txt: block is not closed");
+    }
+
+    #[test]
+    fn test_sequenceparser_activations() {
+        let elements = parse_lines(&[
+            "alice -> bob ++ : hello",
+            "bob -> bob ++",
+            "return",
+            "activate carol",
+            "deactivate carol",
+            "return done",
+            "alice -> bob ++",
+            "bob -> alice --",
+        ]).unwrap();
+
+        let arrow = |origin: &str, target: &str, line_style: &str| Element::new("arrow")
+            .attr("origin", origin)
+            .attr("target", target)
+            .attr("line-style", line_style)
+            .attr("arrow-style", "normal");
+        let activate = |alias: &str| Element::new("activate").attr("participant", alias);
+        let deactivate = |alias: &str| Element::new("deactivate").attr("participant", alias);
+        let expected = rcc(Element::new("sequencediagram:content")
+            .child(arrow("alice", "bob", "normal").child(Element::new_str("text", "hello")))
+            .child(activate("bob"))
+            .child(arrow("bob", "bob", "normal"))
+            .child(activate("bob"))
+            .child(arrow("bob", "bob", "dotted"))
+            .child(deactivate("bob"))
+            .child(activate("carol"))
+            .child(deactivate("carol"))
+            .child(arrow("bob", "alice", "dotted").child(Element::new_str("text", "done")))
+            .child(deactivate("bob"))
+            .child(arrow("alice", "bob", "normal"))
+            .child(activate("bob"))
+            .child(arrow("bob", "alice", "normal"))
+            .child(deactivate("bob"))
+        );
+        assert_eq!(elements[1], expected);
+    }
+
+    #[test]
+    fn test_sequenceparser_activation_errors() {
+        let error = |lines: &[&'static str]| parse_lines(lines).unwrap_err().to_string();
+
+        assert_eq!(error(&["alice -> bob", "return"]), "file.txt: 'return' without activation");
+        assert_eq!(error(&["activate bob", "return"]), "file.txt: don't know where 'bob' returns to");
+        assert_eq!(error(&["alice -> bob --"]), "file.txt: 'alice' is not active");
+        assert_eq!(error(&["activate alice", "deactivate bob"]), "file.txt: 'bob' is not active");
+        assert_eq!(error(&["deactivate"]), "file.txt: no active participant");
+        assert_eq!(error(&["activate alice bob"]), "file.txt: unexpected token 'bob'");
+    }
+
+    #[test]
+    fn test_sequenceparser_lifecycle() {
+        let elements = parse_lines(&[
+            "participant alice",
+            "create control bob",
+            "alice -> bob",
+            "alice -> carol ** : new",
+            "carol -> alice",
+            "alice -> bob !!",
+            "destroy carol",
+        ]).unwrap();
+
+        let arrow = |origin: &str, target: &str| Element::new("arrow")
+            .attr("origin", origin)
+            .attr("target", target)
+            .attr("line-style", "normal")
+            .attr("arrow-style", "normal");
+        let expected_header = rcc(Element::new("sequencediagram:header")
+            .child(Element::new("participant")
+                .attr("alias", "alice")
+                .child(Element::new_str("name", "alice"))
+            )
+            .child(Element::new("control")
+                .attr("alias", "bob")
+                .attr("created", "true")
+                .child(Element::new_str("name", "bob"))
+            )
+            .child(Element::new("participant")
+                .attr("alias", "carol")
+                .attr("created", "true")
+                .child(Element::new_str("name", "carol"))
+            )
+        );
+        let expected_content = rcc(Element::new("sequencediagram:content")
+            .child(Element::new("create").attr("participant", "bob"))
+            .child(arrow("alice", "bob"))
+            .child(Element::new("create").attr("participant", "carol"))
+            .child(arrow("alice", "carol").child(Element::new_str("text", "new")))
+            .child(arrow("carol", "alice"))
+            .child(arrow("alice", "bob"))
+            .child(Element::new("destroy").attr("participant", "bob"))
+            .child(Element::new("destroy").attr("participant", "carol"))
+        );
+        assert_eq!(elements[0], expected_header);
+        assert_eq!(elements[1], expected_content);
+    }
+
+    #[test]
+    fn test_sequenceparser_lifecycle_errors() {
+        let error = |lines: &[&'static str]| parse_lines(lines).unwrap_err().to_string();
+
+        assert_eq!(error(&["create bob", "create bob"]), "file.txt: 'bob' is already created");
+        assert_eq!(error(&["alice -> bob", "create bob"]),
+            "file.txt: 'bob' can't be created, it's already in the sequence");
+        assert_eq!(error(&["alice -> bob !!", "bob -> alice"]), "file.txt: 'bob' is destroyed");
+        assert_eq!(error(&["alice -> bob", "destroy bob", "destroy bob"]), "file.txt: 'bob' is already destroyed");
+        assert_eq!(error(&["alice -> bob", "destroy carol"]), "file.txt: unknown participant 'carol'");
+        assert_eq!(error(&["create"]), "file.txt: expecting participant name");
+    }
+
+    #[test]
+    fn test_sequenceparser_notes() {
+        let elements = parse_lines(&[
+            "alice -> bob",
+            "note left of alice : **hello**",
+            "hnote over alice, \"bob\"",
+            "  line *1*",
+            "  line 2",
+            "end hnote",
+            "rnote right bob:",
+        ]).unwrap();
+
+        let paragraph = |child: Element| Element::new("format")
+            .attr("format", "paragraph")
+            .child(child);
+        let expected = rcc(Element::new("sequencediagram:content")
+            .child(Element::new("arrow")
+                .attr("origin", "alice")
+                .attr("target", "bob")
+                .attr("line-style", "normal")
+                .attr("arrow-style", "normal")
+            )
+            .child(Element::new("note")
+                .attr("shape", "note")
+                .attr("position", "left")
+                .attr("participant", "alice")
+                .child(paragraph(Element::new("format")
+                    .attr("format", "bold")
+                    .child(Element::str("hello"))
+                ))
+            )
+            .child(Element::new("note")
+                .attr("shape", "hnote")
+                .attr("position", "over")
+                .attr("participant", "alice")
+                .attr("to", "bob")
+                .child(paragraph(Element::str("line "))
+                    .child(Element::new("format")
+                        .attr("format", "italic")
+                        .child(Element::str("1"))
+                    )
+                )
+                .child(paragraph(Element::str("line 2")))
+            )
+            .child(Element::new("note")
+                .attr("shape", "rnote")
+                .attr("position", "right")
+                .attr("participant", "bob")
+            )
+        );
+        assert_eq!(elements[1], expected);
+    }
+
+    #[test]
+    fn test_sequenceparser_note_errors() {
+        let error = |lines: &[&'static str]| parse_lines(lines).unwrap_err();
+
+        let e = error(&["note alice : hi"]);
+        assert_eq!((e.kind, e.column), (ErrorKind::Syntax(String::from("expecting 'left', 'right' or 'over'")), 6));
+        let e = error(&["note left of alice, bob : hi"]);
+        assert_eq!((e.kind, e.column), (ErrorKind::UnexpectedToken(String::from(", bob : hi")), 19));
+        let e = error(&["note over a,b,c : hi"]);
+        assert_eq!((e.kind, e.column), (ErrorKind::Syntax(String::from("a note spans 2 participants at most")), 5));
+        let e = error(&["alice -> bob", "note over alice", "hi"]);
+        assert_eq!(e.kind, ErrorKind::UnclosedBlock);
+    }
+
+    #[test]
+    fn test_sequenceparser_vertical_layout() {
+        let elements = parse_lines(&[
+            "alice -> bob",
+            "...",
+            "... 5 minutes later ...",
+            "|||",
+            "|| 45 ||",
+            "== Second part ==",
+            "====",
+        ]).unwrap();
+
+        let expected = rcc(Element::new("sequencediagram:content")
+            .child(Element::new("arrow")
+                .attr("origin", "alice")
+                .attr("target", "bob")
+                .attr("line-style", "normal")
+                .attr("arrow-style", "normal")
+            )
+            .child(Element::new("delay"))
+            .child(Element::new("delay").child(Element::new_str("text", "5 minutes later")))
+            .child(Element::new("space"))
+            .child(Element::new("space").attr("height", "45"))
+            .child(Element::new("separator").child(Element::new_str("text", "Second part")))
+            .child(Element::new("separator"))
+        );
+        assert_eq!(elements[1], expected);
+    }
+
+    #[test]
+    fn test_sequenceparser_vertical_layout_errors() {
+        let error = |line: &'static str| parse_lines(&[line]).unwrap_err();
+
+        let e = error("... later");
+        assert_eq!((e.kind, e.column), (ErrorKind::Syntax(String::from("expecting '...' at the end of the line")), 10));
+        let e = error("== Section");
+        assert_eq!((e.kind, e.column), (ErrorKind::Syntax(String::from("expecting '==' at the end of the line")), 11));
+        let e = error("||45");
+        assert_eq!((e.kind, e.column), (ErrorKind::Syntax(String::from("expecting '||height||'")), 3));
+        let e = error("||a lot||");
+        assert_eq!((e.kind, e.column), (ErrorKind::Syntax(String::from("invalid height 'a lot'")), 3));
+        let e = error("||| more");
+        assert_eq!((e.kind, e.column), (ErrorKind::UnexpectedToken(String::from("more")), 5));
+    }
+
+    #[test]
+    fn test_sequenceparser_header_options() {
+        let elements = parse_lines(&[
+            "title The \\\"title\\\"",
+            "hide footbox",
+            "participant alice",
+            "show footbox",
+            "hide unlinked",
+            "alice -> bob",
+        ]).unwrap();
+
+        let expected = rcc(Element::new("sequencediagram:header")
+            .attr("footbox", "show")
+            .attr("unlinked", "hide")
+            .child(Element::new_str("title", "The \"title\""))
+            .child(Element::new("participant")
+                .attr("alias", "alice")
+                .child(Element::new_str("name", "alice"))
+            )
+            .child(Element::new("participant")
+                .attr("alias", "bob")
+                .child(Element::new_str("name", "bob"))
+            )
+        );
+        assert_eq!(elements[0], expected);
+
+        let error = |line: &'static str| parse_lines(&[line]).unwrap_err();
+        let e = error("title");
+        assert_eq!(e.kind, ErrorKind::Syntax(String::from("expecting title text")));
+        let e = error("hide everything");
+        assert_eq!((e.kind, e.column), (ErrorKind::Syntax(String::from("expecting 'footbox' or 'unlinked' after 'hide'")), 6));
+    }
+
+    #[test]
+    fn test_sequenceparser_header_keyword_prefix() {
+        // a participant whose name starts with a header keyword is a message, not a header line
+        for line in ["titleService -> bob : hi", "hideout -> bob", "showroom -> bob"] {
+            let elements = parse_lines(&["participant bob", line]).unwrap();
+            let name = line.split(' ').next().unwrap();
+
+            let expected = rcc(Element::new("sequencediagram:header")
+                .child(Element::new("participant")
+                    .attr("alias", "bob")
+                    .child(Element::new_str("name", "bob"))
+                )
+                .child(Element::new("participant")
+                    .attr("alias", name)
+                    .child(Element::new_str("name", name))
+                )
+            );
+            assert_eq!(elements[0], expected);
+            let content = elements[1].borrow();
+            match &content.content {
+                ElementContent::Tree(tree) => {
+                    assert_eq!(tree.children.len(), 1);
+                    assert_eq!(tree.children[0].borrow().get_tag(), "arrow");
+                    assert_eq!(tree.children[0].borrow().get_attr("origin"), Some(String::from(name)));
+                },
+                _ => panic!("content shall be a tree"),
+            }
+        }
+    }
+
+    #[test]
+    fn test_sequenceparser_box_color() {
+        let elements = parse_lines(&[
+            "box \"Front\" #LightBlue",
+            "  participant alice",
+            "  box back #c0FFee",
+            "    participant bob",
+            "  end box",
+            "end box",
+        ]).unwrap();
+
+        let expected = rcc(Element::new("sequencediagram:header")
+            .child(Element::new("box")
+                .attr("color", "lightblue")
+                .child(Element::new_str("name", "Front"))
+                .child(Element::new("participant")
+                    .attr("alias", "alice")
+                    .child(Element::new_str("name", "alice"))
+                )
+                .child(Element::new("box")
+                    .attr("color", "#c0FFee")
+                    .child(Element::new_str("name", "back"))
+                    .child(Element::new("participant")
+                        .attr("alias", "bob")
+                        .child(Element::new_str("name", "bob"))
+                    )
+                )
+            )
+        );
+        assert_eq!(elements[0], expected);
+
+        let error = |line: &'static str| parse_lines(&[line]).unwrap_err();
+        let e = error("box b #");
+        assert_eq!((e.kind, e.column), (ErrorKind::Syntax(String::from("invalid color '#'")), 7));
+        let e = error("box b #dark-red");
+        assert_eq!((e.kind, e.column), (ErrorKind::Syntax(String::from("invalid color '#dark-red'")), 7));
+        let e = error("box b red");
+        assert_eq!((e.kind, e.column), (ErrorKind::UnexpectedToken(String::from("red")), 7));
+        let e = error("box b #red now");
+        assert_eq!((e.kind, e.column), (ErrorKind::UnexpectedToken(String::from("now")), 12));
+    }
+
+    #[test]
+    fn test_sequenceparser_arrow_styles() {
+        let elements = parse_lines(&[
+            "alice -->> bob",
+            "alice <<-- bob",
+            "alice -\\ bob",
+            "alice ->x bob",
+            "alice o<-- bob",
+        ]).unwrap();
+
+        // the name on the left comes first
+        let arrow = |left: (&str, &str), right: (&str, &str), line: &str, head: &str| Element::new("arrow")
+            .attr(left.0, left.1)
+            .attr(right.0, right.1)
+            .attr("line-style", line)
+            .attr("arrow-style", head);
+        let expected = rcc(Element::new("sequencediagram:content")
+            .child(arrow(("origin", "alice"), ("target", "bob"), "dotted", "fine"))
+            .child(arrow(("target", "alice"), ("origin", "bob"), "dotted", "fine"))
+            .child(arrow(("origin", "alice"), ("target", "bob"), "normal", "top"))
+            .child(arrow(("origin", "alice"), ("target", "bob"), "normal", "normal").attr("decor", "cross"))
+            .child(arrow(("target", "alice"), ("origin", "bob"), "dotted", "normal").attr("decor", "round"))
+        );
+        assert_eq!(elements[1], expected);
+    }
+
+    #[test]
+    fn test_sequenceparser_border_messages() {
+        let elements = parse_lines(&[
+            "[-> api ++ : GET /items",
+            "api ->] : audit",
+            "[<- api -- : done",
+        ]).unwrap();
+
+        let expected = rcc(Element::new("sequencediagram:content")
+            .child(Element::new("arrow")
+                .child(Element::new_str("text", "GET /items"))
+                .attr("target", "api")
+                .attr("line-style", "normal")
+                .attr("arrow-style", "normal")
+                .attr("border", "left")
+            )
+            .child(Element::new("activate").attr("participant", "api"))
+            .child(Element::new("arrow")
+                .child(Element::new_str("text", "audit"))
+                .attr("origin", "api")
+                .attr("line-style", "normal")
+                .attr("arrow-style", "normal")
+                .attr("border", "right")
+            )
+            .child(Element::new("arrow")
+                .child(Element::new_str("text", "done"))
+                .attr("origin", "api")
+                .attr("line-style", "normal")
+                .attr("arrow-style", "normal")
+                .attr("border", "left")
+            )
+            .child(Element::new("deactivate").attr("participant", "api"))
+        );
+        assert_eq!(elements[1], expected);
+
+        let error = |lines: &[&'static str]| parse_lines(lines).unwrap_err().to_string();
+        assert_eq!(error(&["[-> ]"]), "file.txt: a message can't go from a border to the other");
+        assert_eq!(error(&["api ->] ++"]), "file.txt: '++' needs a target");
+        assert_eq!(error(&["[-> api --"]), "file.txt: '--' needs an origin");
+    }
+
+    #[test]
+    fn test_sequenceparser_autonumber() {
+        let elements = parse_lines(&[
+            "autonumber 10 5 \"<b>[000]\"",
+            "participant alice",
+            "alice -> bob ++ : hello",
+            "return hi",
+            "autonumber stop",
+            "alice -> bob",
+            "autonumber resume 1 \"#.\"",
+            "alice -> bob",
+            "bob -> alice",
+        ]).unwrap();
+
+        let number = |e: &Rcc<Element>| (e.borrow().get_attr("number"), e.borrow().get_attr("number-style"));
+        let arrows: Vec<Rcc<Element>> = match &elements[1].borrow().content {
+            ElementContent::Tree(content) => content.children.iter()
+                .filter(|e| e.borrow().get_tag() == "arrow").cloned().collect(),
+            _ => vec![],
+        };
+        let numbers: Vec<(Option<String>, Option<String>)> = arrows.iter().map(number).collect();
+        let some = |n: &str, style: &str| (Some(String::from(n)), Some(String::from(style)));
+        assert_eq!(numbers, vec![
+            some("[010]", "bold"),
+            some("[015]", "bold"),
+            (None, None),
+            some("20.", "normal"),
+            some("21.", "normal"),
+        ]);
+
+        let error = |lines: &[&'static str]| parse_lines(lines).unwrap_err().to_string();
+        assert_eq!(error(&["autonumber stop"]), "file.txt: 'autonumber stop' without autonumber");
+        assert_eq!(error(&["autonumber resume"]), "file.txt: 'autonumber resume' without autonumber");
+        assert_eq!(error(&["autonumber ten"]), "file.txt: invalid autonumber 'ten'");
+        assert_eq!(error(&["autonumber 1 2 3"]), "file.txt: invalid autonumber '3'");
+
+        // 'autonumberer' is a participant, in the header and in the content
+        for lines in [&["autonumberer -> bob"][..], &["alice -> bob", "autonumberer -> bob"][..]] {
+            let elements = parse_lines(lines).unwrap();
+            let origins: Vec<Option<String>> = match &elements[1].borrow().content {
+                ElementContent::Tree(content) => content.children.iter()
+                    .map(|e| e.borrow().get_attr("origin")).collect(),
+                _ => vec![],
+            };
+            assert_eq!(origins.last(), Some(&Some(String::from("autonumberer"))));
+            assert_eq!(origins.len(), lines.len());
+        }
+    }
+
+    #[test]
+    fn test_format_number() {
+        assert_eq!(format_number(7, "<b>#</b>"), (String::from("7"), true));
+        assert_eq!(format_number(7, "[000]"), (String::from("[007]"), false));
+        assert_eq!(format_number(1234, "00"), (String::from("1234"), false));
+        assert_eq!(format_number(3, "step "), (String::from("step 3"), false));
+    }
+
+    #[test]
+    fn test_sequenceparser_constraints() {
+        let elements = parse_lines(&[
+            "{req} alice -> bob : GET",
+            "{ resp } bob --> alice",
+            "{req} <-> {resp} : 200 ms max",
+            "{resp} <-> {req}",
+        ]).unwrap();
+
+        let expected = rcc(Element::new("sequencediagram:content")
+            .child(Element::new("arrow")
+                .child(Element::new_str("text", "GET"))
+                .attr("origin", "alice")
+                .attr("target", "bob")
+                .attr("line-style", "normal")
+                .attr("arrow-style", "normal")
+                .attr("id", "req")
+            )
+            .child(Element::new("arrow")
+                .attr("origin", "bob")
+                .attr("target", "alice")
+                .attr("line-style", "dotted")
+                .attr("arrow-style", "normal")
+                .attr("id", "resp")
+            )
+            .child(Element::new("constraint")
+                .attr("start", "req")
+                .attr("end", "resp")
+                .child(Element::new_str("text", "200 ms max"))
+            )
+            .child(Element::new("constraint")
+                .attr("start", "resp")
+                .attr("end", "req")
+            )
+        );
+        assert_eq!(elements[1], expected);
+
+        let error = |lines: &[&'static str]| parse_lines(lines).unwrap_err().to_string();
+        assert_eq!(error(&["{req alice -> bob"]), "file.txt: unfinished {id}");
+        assert_eq!(error(&["{} alice -> bob"]), "file.txt: empty {id}");
+        assert_eq!(error(&["{req} alice -> bob", "{req} bob -> alice"]), "file.txt: message id 'req' is already used");
+        assert_eq!(error(&["{req} alice -> bob", "{req} <-> {resp}"]), "file.txt: unknown message id 'resp'");
+        assert_eq!(error(&["{req} alice -> bob", "{req} <-> resp"]), "file.txt: expecting '{id}' after '<->'");
+    }
 }
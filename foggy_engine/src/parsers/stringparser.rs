@@ -3,6 +3,7 @@ use std::cell::{RefCell};
 use crate::datatypes::{SliceWithContext, Element, Document};
 use crate::parsers::datatypes::{Parser, ParserResult};
 use crate::parseutils::*;
+use crate::errors::{ErrorKind, FoggyError};
 
 pub struct StringParser {
     collec: Option<String>,
@@ -31,15 +32,12 @@ impl Parser for StringParser {
     fn step<'a>(
         &mut self,
         input: &'a mut SliceWithContext<'a>,
-    ) -> Result<ParserResult<'a>, (&'a SliceWithContext<'a>, String)> {
+    ) -> Result<ParserResult<'a>, FoggyError> {
         let mut slice: &str = input.slice;
 
         if self.collec == None {
             // That means this stringparser instance has already been used
-            return Err((
-                input,
-                String::from("runtime error, collector shall not be none"),
-            ));
+            return Err(input.error_at(slice, ErrorKind::Internal(String::from("collector shall not be none"))));
         }
 
         if self.start_token.len() == 0 {
@@ -49,7 +47,7 @@ impl Parser for StringParser {
                     self.start_token.push_str(token);
                     slice = new_slice;
                 }
-                Err(_) => return Err((input, String::from("Invalid string start token"))),
+                Err(_) => return Err(input.error_at(slice, ErrorKind::Syntax(String::from("invalid string start token")))),
             }
         }
 
@@ -79,7 +77,7 @@ impl Parser for StringParser {
                         slice = &new_slice[c.len_utf8()..];
                     } else {
                         // multiline string!
-                        return Err((input, String::from("not implemented yet")));
+                        return Err(input.error_at(slice, ErrorKind::NotImplemented(String::from("multiline string"))));
                     }
                 } else if consumed == self.start_token {
                     // We finished parsing the string, return its content
@@ -88,8 +86,8 @@ impl Parser for StringParser {
                 }
             }
         }
-        // Full line was consumed
-        return Err((input, String::from("invalid token")));
+        // Full line was consumed, the string starts at the beginning of the input slice
+        return Err(input.error_at(input.slice, ErrorKind::UnfinishedString));
     }
 
     fn flush(&mut self) -> (Vec<Rc<RefCell<Element>>>, Vec<Rc<RefCell<Document>>>) {
@@ -125,6 +123,7 @@ mod tests {
             line: 0,
             pos: 0,
             file_name: Rc::new(String::from("file.txt")),
+            namespace: Rc::new(String::new()),
         };
 
         let returned = parser.step(&mut slice);
@@ -150,6 +149,7 @@ mod tests {
             line: 0,
             pos: 0,
             file_name: Rc::new(String::from("file.txt")),
+            namespace: Rc::new(String::new()),
         };
 
         let returned = parser.step(&mut slice);
@@ -175,6 +175,7 @@ mod tests {
             line: 0,
             pos: 0,
             file_name: Rc::new(String::from("file.txt")),
+            namespace: Rc::new(String::new()),
         };
 
         let returned = parser.step(&mut slice);
@@ -191,6 +192,7 @@ mod tests {
             line: 0,
             pos: 0,
             file_name: Rc::new(String::from("file.txt")),
+            namespace: Rc::new(String::new()),
         };
 
         let returned = parser.step(&mut slice);
@@ -214,6 +216,7 @@ mod tests {
             line: 0,
             pos: 0,
             file_name: Rc::new(String::from("file.txt")),
+            namespace: Rc::new(String::new()),
         };
 
         let returned = parser.step(&mut slice);
@@ -237,6 +240,7 @@ mod tests {
             line: 0,
             pos: 0,
             file_name: Rc::new(String::from("file.txt")),
+            namespace: Rc::new(String::new()),
         };
 
         let returned = parser.step(&mut slice);
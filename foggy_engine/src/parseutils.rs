@@ -26,6 +26,26 @@ pub fn consume_token_in_list<'a, 'b>(
     return Err(input);
 }
 
+// Same as consume_token_in_list, but a token that ends with a letter or a digit
+// shall not be followed by an other letter or digit: 'end' doesn't match 'endpoint'
+pub fn consume_keyword_in_list<'a, 'b>(
+    input: &'a str,
+    tokens: &'b [&str],
+) -> Result<(&'a str, &'a str), &'a str> {
+    for token in tokens {
+        if let Ok((remaining, consumed)) = consume_token_in_list(input, &[token]) {
+            let is_word = |c: char| c.is_alphanumeric() || c == '_';
+            let token_is_word = consumed.chars().last().map_or(false, is_word);
+            let followed_by_word = remaining.chars().next().map_or(false, is_word);
+            if !(token_is_word && followed_by_word) {
+                return Ok((remaining, consumed));
+            }
+        }
+    }
+    // No token matched
+    return Err(input);
+}
+
 pub fn consume_until_token_in_list<'a, 'b>(
     input: &'a str,
     tokens: &'b [&str],
@@ -123,6 +143,17 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_consume_keyword_in_list() {
+        assert_eq!(consume_keyword_in_list("end", &["end"]), Ok(("", "end")));
+        assert_eq!(consume_keyword_in_list("end box", &["end"]), Ok((" box", "end")));
+        assert_eq!(consume_keyword_in_list("endpoint", &["end"]), Err("endpoint"));
+        assert_eq!(consume_keyword_in_list("alt[ok]", &["alt"]), Ok(("[ok]", "alt")));
+        assert_eq!(consume_keyword_in_list("....", &["..."]), Ok((".", "...")));
+        // an other token can still match
+        assert_eq!(consume_keyword_in_list("ends", &["end", "ends"]), Ok(("", "ends")));
+    }
+
     #[test]
     fn test_consume_until_token_in_list() {
         assert_eq!(
@@ -1,21 +1,76 @@
+use std::collections::{HashMap, VecDeque};
 use std::io::{BufRead, Lines};
 use std::iter::Iterator;
+use std::path::Path;
 use std::rc::Rc;
 
 use crate::datatypes::LineWithContext;
 use crate::parseutils::*;
+use crate::errors::{ErrorKind, FoggyError};
 
 // a data source for the preprocessor
 // typically a wrapper around file access
 // but we can add : in memory data source, cache, file access security checks, etc.
 pub trait Datasource {
-    fn get_data(&self, filename: &str) -> Result<Box<dyn BufRead>, &'static str>;
+    // Turn a file name as written in the source into the name used to get the data.
+    // parent is the name of the file that imports/includes it, None for top level files.
+    // This is where a datasource can reject a file, before anything is read
+    fn resolve(&self, filename: &str, _parent: Option<&str>) -> Result<String, FoggyError> {
+        Ok(String::from(filename))
+    }
+    fn get_data(&self, filename: &str) -> Result<Box<dyn BufRead>, FoggyError>;
+}
+
+// An /if or /ifdef block that is currently open
+struct Condition {
+    line: u32,           // where the block was opened, for error reporting
+    taken: bool,         // value of the condition
+    in_else: bool,       // after the /else
+    parent_active: bool, // false if an enclosing block is already ignored
+}
+
+impl Condition {
+    fn new(line: u32, taken: bool, parent_active: bool) -> Condition {
+        Condition { line, taken, in_else: false, parent_active }
+    }
+
+    fn is_active(&self) -> bool {
+        self.parent_active && (self.taken != self.in_else)
+    }
+}
+
+// A macro defined with /macro, expanded when a line calls it:
+// /macro call(a, b) a -> b : b
+// call(alice, bob)
+struct Macro {
+    params: Vec<String>,
+    body: Vec<String>,
+    // where it was defined, for the note on expanded lines
+    file_name: Rc<String>,
+    line: u32,
 }
 
 struct StackContext {
     lines: Lines<Box<dyn BufRead>>,
     filename: Rc<String>,
     namespace: Rc<String>,
+    line: u32, // number of the last line read from this file, starts at 1
+    conditions: Vec<Condition>, // blocks can't span several files
+    // multi-line macro being defined, until /endmacro
+    macro_definition: Option<(String, Macro)>,
+    // number of backticks of the fenced block being read, and if it is code.
+    // Lines of code are taken as is until the block closes
+    fence: Option<(usize, bool)>,
+}
+
+impl StackContext {
+    // Shall the current line be processed or ignored
+    fn is_active(&self) -> bool {
+        match self.conditions.last() {
+            None => true,
+            Some(c) => c.is_active(),
+        }
+    }
 }
 
 pub struct Preprocessor<'a> {
@@ -23,8 +78,16 @@ pub struct Preprocessor<'a> {
     stack: Vec<StackContext>,
     processed_files: Vec<String>,
     files: Vec<String>,
+    defines: HashMap<String, String>,
+    macros: HashMap<String, Rc<Macro>>,
+    // lines ready to be returned before reading anything else, for /includecode
+    pending: VecDeque<LineWithContext>,
+    // maximum number of nested files, top level file included
+    max_include_depth: usize,
 }
 
+pub const DEFAULT_MAX_INCLUDE_DEPTH: usize = 32;
+
 impl<'a> Preprocessor<'a> {
     pub fn new(input: &'a dyn Datasource, files: &[&str]) -> Preprocessor<'a> {
         let mut obj = Preprocessor {
@@ -32,19 +95,115 @@ impl<'a> Preprocessor<'a> {
             stack: Vec::new(),
             processed_files: Vec::new(),
             files: Vec::new(),
+            defines: HashMap::new(),
+            macros: HashMap::new(),
+            pending: VecDeque::new(),
+            max_include_depth: DEFAULT_MAX_INCLUDE_DEPTH,
         };
 
         //println!("** new preprocessor with list of files:");
         for file in files {
-            println!("-{}", file);
+            //println!("-{}", file);
             obj.files.push(file.to_string());
         }
         return obj;
     }
 
+    // Same as a /define statement at the top of the first file
+    pub fn define(&mut self, name: &str, value: &str) {
+        self.defines.insert(String::from(name), String::from(value));
+    }
+
+    pub fn set_max_include_depth(&mut self, depth: usize) {
+        self.max_include_depth = depth;
+    }
+
+    // Check that file can be pushed on top of the stack.
+    // ctx is the file that includes it, already out of the stack
+    fn check_include(&self, ctx: &StackContext, file: &str) -> Result<(), ErrorKind> {
+        let mut chain: Vec<&str> = self.stack.iter().map(|c| c.filename.as_str()).collect();
+        chain.push(&ctx.filename);
+
+        if chain.iter().any(|f| *f == file) {
+            chain.push(file);
+            return Err(ErrorKind::CircularInclude(chain.iter().map(|f| f.to_string()).collect()));
+        }
+        if chain.len() >= self.max_include_depth {
+            return Err(ErrorKind::IncludeTooDeep(self.max_include_depth));
+        }
+        return Ok(());
+    }
+
     fn file_already_processed(&self, file: &str) -> bool {
         self.processed_files.iter().any(|e| e == file)
     }
+
+    // Read the file of an /includecode statement and queue its lines, wrapped in a code fence.
+    // ctx is the file that contains the statement
+    fn include_code(&mut self, ctx: &StackContext, statement: &IncludeCodeStatement) -> Result<(), FoggyError> {
+        let file = self.input.resolve(statement.file, Some(&ctx.filename))?;
+        let file_name = Rc::new(file);
+
+        let mut lines = vec![];
+        for line in self.input.get_data(&file_name)?.lines() {
+            match line {
+                Ok(l) => lines.push(l),
+                Err(e) => {
+                    let kind = ErrorKind::Io(format!("cannot read '{}': {}", file_name, e));
+                    return Err(FoggyError::at(kind, &file_name, lines.len() as u32 + 1, 0));
+                }
+            }
+        }
+        let selected = select_code_lines(&lines, statement, &file_name)?;
+
+        // The fence must be longer than any backtick sequence in the code,
+        // so that the code can't close the block itself
+        let longest = selected.iter().map(|(_, l)| longest_backtick_run(l)).max().unwrap_or(0);
+        let fence = "`".repeat(std::cmp::max(3, longest + 1));
+
+        let line_at = |text: String, file_name: &Rc<String>, line: u32| LineWithContext {
+            text,
+            line,
+            file_name: Rc::clone(file_name),
+            namespace: Rc::clone(&ctx.namespace),
+            note: None,
+        };
+        self.pending.push_back(line_at(format!("{}{}", fence, statement.tag), &ctx.filename, ctx.line));
+        for (number, text) in selected {
+            self.pending.push_back(line_at(text.clone(), &file_name, number));
+        }
+        self.pending.push_back(line_at(fence, &ctx.filename, ctx.line));
+        return Ok(());
+    }
+
+    // Queue the lines of a macro call. The lines keep the location of the call,
+    // with a note about the definition of the macro.
+    // Expanded lines are not checked for other macro calls
+    fn expand_macro(&mut self, ctx: &StackContext, name: &str, args: &[&str]) -> Result<(), FoggyError> {
+        let definition = Rc::clone(&self.macros[name]);
+        if args.len() != definition.params.len() {
+            return Err(FoggyError::at(
+                ErrorKind::Syntax(format!("macro '{}' expects {} arguments, got {}", name, definition.params.len(), args.len())),
+                &ctx.filename, ctx.line, 1));
+        }
+
+        let note = Rc::new(format!("expanded from macro '{}' defined at {}:{}", name, definition.file_name, definition.line));
+        for line in &definition.body {
+            let text = substitute_words(line, &definition.params, args);
+            let text = match substitute_defines(&text, &self.defines) {
+                Ok(t) => t,
+                Err((_, kind)) => return Err(FoggyError::at(kind, &ctx.filename, ctx.line, 0).with_note(&note)),
+            };
+            self.pending.push_back(LineWithContext {
+                text,
+                line: ctx.line,
+                file_name: Rc::clone(&ctx.filename),
+                namespace: Rc::clone(&ctx.namespace),
+                note: Some(Rc::clone(&note)),
+            });
+        }
+        return Ok(());
+    }
 }
 
 // Returm None if simply no match
@@ -66,18 +225,351 @@ fn parse_import_include_statement(input: &str) -> Option<Result<(&str, &str), &'
     return Some(Err("Invalid statement"));
 }
 
+#[derive(Debug, PartialEq)]
+enum ConditionStatement<'s> {
+    Define(&'s str, &'s str), // /define NAME value, value can be empty
+    IfDef(&'s str),           // /ifdef NAME
+    IfEq(&'s str, &'s str),   // /if NAME == value
+    Else,
+    EndIf,
+}
+
+// Same return values as parse_import_include_statement
+fn parse_condition_statement(input: &str) -> Option<Result<ConditionStatement<'_>, &'static str>> {
+    let (rem, token) = match consume_token_in_list(&input, &["/define", "/ifdef", "/if", "/else", "/endif"]) {
+        Err(_s) => return None,
+        Ok(r) => r,
+    };
+    let (rem, spaces) = consume_whitespaces(rem);
+    if rem.len() > 0 && spaces.len() == 0 {
+        // eg: /iffy, not one of our statements
+        return None;
+    }
+
+    let (rem, name) = consume_until_whitespace(rem).unwrap();
+    let (rem, _) = consume_whitespaces(rem);
+    let value = rem.trim_end();
+    match token {
+        "/define" if name.len() > 0 => return Some(Ok(ConditionStatement::Define(name, value))),
+        "/ifdef" if name.len() > 0 && value.len() == 0 => return Some(Ok(ConditionStatement::IfDef(name))),
+        "/if" if name.len() > 0 => {
+            if let Ok((rem, _)) = consume_token_in_list(value, &["=="]) {
+                let (value, _) = consume_whitespaces(rem);
+                if value.len() > 0 {
+                    return Some(Ok(ConditionStatement::IfEq(name, value)));
+                }
+            }
+        }
+        "/else" if name.len() == 0 => return Some(Ok(ConditionStatement::Else)),
+        "/endif" if name.len() == 0 => return Some(Ok(ConditionStatement::EndIf)),
+        _ => (),
+    }
+    return Some(Err("Invalid statement"));
+}
+
+// Namespace of an imported file: the file name without directory and extension,
+// under the namespace of the file that imports it.
+// eg: /import common/lib.fgu from the top level file gives lib
+fn import_namespace(parent: &str, name: &str) -> String {
+    let stem = match Path::new(name).file_stem() {
+        Some(s) => s.to_string_lossy().into_owned(),
+        None => String::from(name),
+    };
+    if parent.len() == 0 {
+        return stem;
+    }
+    return format!("{}.{}", parent, stem);
+}
+
+fn is_identifier(input: &str) -> bool {
+    let mut chars = input.chars();
+    match chars.next() {
+        Some(c) if c.is_alphabetic() || c == '_' => chars.all(|c| c.is_alphanumeric() || c == '_'),
+        _ => false,
+    }
+}
+
+// name(a, b) at the start of input
+// Returns the name, the arguments, and the rest of the input
+fn parse_call(input: &str) -> Option<(&str, Vec<&str>, &str)> {
+    let open = input.find('(')?;
+    let close = open + input[open..].find(')')?;
+    let name = &input[..open];
+    if !is_identifier(name) {
+        return None;
+    }
+    let args = match input[open + 1..close].trim() {
+        "" => vec![],
+        args => args.split(',').map(|a| a.trim()).collect(),
+    };
+    return Some((name, args, &input[close + 1..]));
+}
+
+#[derive(Debug, PartialEq)]
+enum MacroStatement<'s> {
+    Macro(&'s str, Vec<&'s str>, Option<&'s str>), // /macro name(a, b) [one line body]
+    EndMacro,
+}
+
+// Same return values as parse_import_include_statement
+fn parse_macro_statement(input: &str) -> Option<Result<MacroStatement<'_>, &'static str>> {
+    let (rem, token) = match consume_token_in_list(&input, &["/macro", "/endmacro"]) {
+        Err(_s) => return None,
+        Ok(r) => r,
+    };
+    let (rem, spaces) = consume_whitespaces(rem);
+    if rem.len() > 0 && spaces.len() == 0 {
+        return None;
+    }
+
+    if token == "/endmacro" {
+        if rem.trim_end().len() == 0 {
+            return Some(Ok(MacroStatement::EndMacro));
+        }
+    } else if let Some((name, params, body)) = parse_call(rem) {
+        if params.iter().all(|p| is_identifier(p)) {
+            let body = body.trim();
+            let body = if body.len() > 0 { Some(body) } else { None };
+            return Some(Ok(MacroStatement::Macro(name, params, body)));
+        }
+    }
+    return Some(Err("Invalid statement"));
+}
+
+// Replace each word of input that is a parameter by the matching argument
+fn substitute_words(input: &str, params: &[String], args: &[&str]) -> String {
+    let mut output = String::new();
+    let mut word_start: Option<usize> = None;
+
+    // a char that can't be part of a word at the end, to flush the last word
+    for (i, c) in input.char_indices().chain(std::iter::once((input.len(), ' '))) {
+        let in_word = c.is_alphanumeric() || c == '_';
+        match (word_start, in_word) {
+            (None, true) => word_start = Some(i),
+            (Some(start), false) => {
+                let word = &input[start..i];
+                match params.iter().position(|p| p == word) {
+                    Some(n) => output.push_str(args[n]),
+                    None => output.push_str(word),
+                }
+                word_start = None;
+            }
+            _ => (),
+        }
+        if !in_word && i < input.len() {
+            output.push(c);
+        }
+    }
+    return output;
+}
+
+// Replace ${NAME} by the value of NAME.
+// An undefined ${NAME} is left as is, so that shell or template snippets in the text
+// go through, and $${NAME} is written ${NAME} even when NAME is defined.
+// On error, returns the byte offset of the faulty ${
+fn substitute_defines(input: &str, defines: &HashMap<String, String>) -> Result<String, (usize, ErrorKind)> {
+    let mut output = String::new();
+    let mut rem = input;
+
+    while let Some(start) = rem.find("${") {
+        let offset = input.len() - rem.len() + start;
+        if rem[..start].ends_with('$') {
+            // escaped, drop one '$'
+            output.push_str(&rem[..start - 1]);
+            output.push_str("${");
+            rem = &rem[start + 2..];
+            continue;
+        }
+        output.push_str(&rem[..start]);
+        let end = match rem[start..].find('}') {
+            Some(e) => start + e,
+            None => return Err((offset, ErrorKind::Syntax(String::from("unclosed '${'")))),
+        };
+        let name = &rem[start + 2..end];
+        match defines.get(name) {
+            Some(value) => output.push_str(value),
+            None => output.push_str(&rem[start..=end]),
+        }
+        rem = &rem[end + 1..];
+    }
+    output.push_str(rem);
+    return Ok(output);
+}
+
+// /includecode file tag:lang [lines:10-40 | region:name]
+#[derive(Debug, PartialEq)]
+struct IncludeCodeStatement<'s> {
+    file: &'s str,
+    tag: &'s str,
+    lines: Option<(usize, usize)>, // first and last line, starting at 1
+    region: Option<&'s str>,
+}
+
+// Same return values as parse_import_include_statement
+fn parse_includecode_statement(input: &str) -> Option<Result<IncludeCodeStatement<'_>, &'static str>> {
+    let rem = match consume_token_in_list(&input, &["/includecode"]) {
+        Err(_s) => return None,
+        Ok((rem, _)) => rem,
+    };
+    let (rem, spaces) = consume_whitespaces(rem);
+    if rem.len() > 0 && spaces.len() == 0 {
+        return None;
+    }
+
+    let mut words = rem.split_whitespace();
+    let mut statement = IncludeCodeStatement {
+        file: match words.next() {
+            Some(f) => f,
+            None => return Some(Err("Invalid statement")),
+        },
+        tag: "",
+        lines: None,
+        region: None,
+    };
+    for word in words {
+        if let Ok((tag, _)) = consume_token_in_list(word, &["tag:"]) {
+            if statement.tag.len() > 0 || tag.len() == 0 {
+                return Some(Err("Invalid statement"));
+            }
+            statement.tag = tag;
+        } else if let Ok((range, _)) = consume_token_in_list(word, &["lines:"]) {
+            if statement.lines != None {
+                return Some(Err("Invalid statement"));
+            }
+            // lines:10-40 or lines:10
+            let mut bounds = range.splitn(2, '-').map(|n| n.parse::<usize>());
+            let first = match bounds.next() {
+                Some(Ok(n)) if n > 0 => n,
+                _ => return Some(Err("Invalid statement")),
+            };
+            let last = match bounds.next() {
+                None => first,
+                Some(Ok(n)) if n >= first => n,
+                _ => return Some(Err("Invalid statement")),
+            };
+            statement.lines = Some((first, last));
+        } else if let Ok((region, _)) = consume_token_in_list(word, &["region:"]) {
+            if statement.region != None || region.len() == 0 {
+                return Some(Err("Invalid statement"));
+            }
+            statement.region = Some(region);
+        } else {
+            return Some(Err("Invalid statement"));
+        }
+    }
+
+    // the tag is mandatory, and a line range inside a region would be ambiguous
+    if statement.tag.len() == 0 || (statement.lines != None && statement.region != None) {
+        return Some(Err("Invalid statement"));
+    }
+    return Some(Ok(statement));
+}
+
+// Region markers can be anywhere on a line, typically in a comment of the included language:
+// # @region setup
+// ...
+// # @endregion setup
+// Returns the marker token and the region name
+fn parse_region_marker(line: &str) -> Option<(&str, &str)> {
+    let start = line.find("@region").or_else(|| line.find("@endregion"))?;
+    let (rem, token) = consume_token_in_list(&line[start..], &["@region", "@endregion"]).ok()?;
+    let (rem, spaces) = consume_whitespaces(rem);
+    let (_, name) = consume_until_whitespace(rem).unwrap();
+    if spaces.len() == 0 || name.len() == 0 {
+        return None;
+    }
+    return Some((token, name));
+}
+
+// Apply the line range or the region of an /includecode statement.
+// Returns the selected lines with their line number, region markers are removed.
+// Errors without location are reported on the statement by the caller
+fn select_code_lines(
+    lines: &[String],
+    statement: &IncludeCodeStatement,
+    file_name: &Rc<String>,
+) -> Result<Vec<(u32, String)>, FoggyError> {
+    let (first, last) = match (statement.lines, statement.region) {
+        (Some((first, last)), _) => {
+            if last > lines.len() {
+                return Err(FoggyError::new(ErrorKind::Syntax(format!(
+                    "lines {}-{} out of range, '{}' has {} lines", first, last, file_name, lines.len()))));
+            }
+            (first, last)
+        }
+        (None, Some(region)) => {
+            let start = lines.iter().position(|l| parse_region_marker(l) == Some(("@region", region)));
+            let start = match start {
+                Some(s) => s + 1,
+                None => return Err(FoggyError::new(ErrorKind::Syntax(format!(
+                    "region '{}' not found in '{}'", region, file_name)))),
+            };
+            match lines[start..].iter().position(|l| parse_region_marker(l) == Some(("@endregion", region))) {
+                // first and last are line numbers, start is the index of the line after the marker
+                Some(end) => (start + 1, start + end),
+                None => return Err(FoggyError::at(ErrorKind::UnclosedBlock, file_name, start as u32, 1)),
+            }
+        }
+        (None, None) => (1, lines.len()),
+    };
+
+    let mut selected = vec![];
+    for number in first..=last {
+        let line = &lines[number - 1];
+        if parse_region_marker(line) == None {
+            selected.push((number as u32, line.clone()));
+        }
+    }
+    return Ok(selected);
+}
+
+fn longest_backtick_run(line: &str) -> usize {
+    line.split(|c| c != '`').map(|run| run.len()).max().unwrap_or(0)
+}
+
+// ``` or more at the start of the line, returns the number of backticks
+fn fence_length(line: &str) -> usize {
+    let (_, fence) = consume_while_char(line.trim_start(), '`');
+    return if fence.len() >= 3 { fence.len() } else { 0 };
+}
+
+// ``` or more, and nothing else
+fn is_fence_end(line: &str, fence: usize) -> bool {
+    let trimmed = line.trim();
+    return fence_length(trimmed) >= fence && trimmed.chars().all(|c| c == '`');
+}
+
+// Fence that opens a block, with true if the block is code.
+// Diagrams in ```sequence blocks are still preprocessed
+fn fence_start(line: &str) -> Option<(usize, bool)> {
+    let fence = fence_length(line);
+    if fence == 0 {
+        return None;
+    }
+    return Some((fence, line.trim()[fence..].trim() != "sequence"));
+}
+
 impl<'a> Iterator for Preprocessor<'a> {
-    type Item = Result<LineWithContext, &'static str>;
+    type Item = Result<LineWithContext, FoggyError>;
 
-    fn next(&mut self) -> Option<Result<LineWithContext, &'static str>> {
-        let mut result: Option<Result<LineWithContext, &'static str>> = None;
+    fn next(&mut self) -> Option<Result<LineWithContext, FoggyError>> {
+        let mut result: Option<Result<LineWithContext, FoggyError>> = None;
+
+        // lines of an /includecode go first
+        if let Some(line) = self.pending.pop_front() {
+            return Some(Ok(line));
+        }
 
         // no file currently processed, look for work to do in list of files
         if self.stack.len() == 0 {
             match self.files.pop() {
                 // All done
                 None => return None,
-                Some(file) => {
+                Some(name) => {
+                    let file = match self.input.resolve(&name, None) {
+                        Ok(f) => f,
+                        Err(s) => return Some(Err(s)),
+                    };
                     self.processed_files.push(file.clone());
 
                     // if file exists/no error, put line iterator into the stack
@@ -85,7 +577,11 @@ impl<'a> Iterator for Preprocessor<'a> {
                         Ok(d) => self.stack.push(StackContext {
                             lines: d.lines(),
                             filename: Rc::new(file.clone()),
-                            namespace: Rc::new(file.clone()),
+                            namespace: Rc::new(String::new()),
+                            line: 0,
+                            conditions: Vec::new(),
+                            macro_definition: None,
+                            fence: None,
                         }),
                         Err(s) => return Some(Err(s)),
                     }
@@ -99,14 +595,141 @@ impl<'a> Iterator for Preprocessor<'a> {
             let mut ctx = self.stack.pop().unwrap();
 
             //Read line by line
-            while let Some(line) = ctx.lines.next() {
-                let line_contents = line.unwrap();
-                println!("{} : {}", ctx.filename, line_contents);
+            loop {
+                let line = match ctx.lines.next() {
+                    Some(l) => l,
+                    None => {
+                        // End of file, every block opened in this file shall be closed
+                        if let Some((_, m)) = &ctx.macro_definition {
+                            return Some(Err(FoggyError::at(ErrorKind::UnclosedBlock, &ctx.filename, m.line, 1)));
+                        }
+                        if let Some(c) = ctx.conditions.last() {
+                            return Some(Err(FoggyError::at(ErrorKind::UnclosedBlock, &ctx.filename, c.line, 1)));
+                        }
+                        break;
+                    }
+                };
+                // every physical line counts, even the ones we don't forward
+                ctx.line += 1;
+                let line_contents = match line {
+                    Ok(l) => l,
+                    Err(e) => {
+                        let kind = ErrorKind::Io(format!("cannot read '{}': {}", ctx.filename, e));
+                        return Some(Err(FoggyError::at(kind, &ctx.filename, ctx.line, 0)));
+                    }
+                };
+                //println!("{} : {}", ctx.filename, line_contents);
+
+                if let Some((name, mut definition)) = ctx.macro_definition.take() {
+                    // body of a multi-line macro, taken as is
+                    if parse_macro_statement(&line_contents) == Some(Ok(MacroStatement::EndMacro)) {
+                        self.macros.insert(name, Rc::new(definition));
+                    } else {
+                        definition.body.push(line_contents);
+                        ctx.macro_definition = Some((name, definition));
+                    }
+                    continue;
+                } else if let (Some((fence, true)), true) = (ctx.fence, ctx.is_active()) {
+                    // Code in a fenced block is taken as is: no comment, no statement,
+                    // no substitution and no macro call
+                    if is_fence_end(&line_contents, fence) {
+                        ctx.fence = None;
+                    }
+                    result = Some(Ok(LineWithContext {
+                        text: line_contents,
+                        line: ctx.line,
+                        file_name: Rc::clone(&ctx.filename),
+                        namespace: Rc::clone(&ctx.namespace),
+                        note: None,
+                    }));
 
-                // TODO: define, if, endif
-                if line_contents.starts_with("//") {
+                    self.stack.push(ctx);
+                    return result;
+                } else if line_contents.starts_with("//") {
                     //comment, ignore line
                     continue;
+                } else if let Some(result) = parse_condition_statement(&line_contents) {
+                    // /define, /ifdef, /if, /else, /endif
+                    // blocks are tracked even when they are ignored, to find the matching /endif
+                    let statement = match result {
+                        Err(_) => return Some(Err(FoggyError::at(ErrorKind::InvalidStatement, &ctx.filename, ctx.line, 1))),
+                        Ok(s) => s,
+                    };
+                    let active = ctx.is_active();
+                    match statement {
+                        ConditionStatement::Define(name, value) => {
+                            if active {
+                                self.define(name, value);
+                            }
+                        }
+                        ConditionStatement::IfDef(name) => {
+                            let taken = self.defines.contains_key(name);
+                            ctx.conditions.push(Condition::new(ctx.line, taken, active));
+                        }
+                        ConditionStatement::IfEq(name, value) => {
+                            let taken = self.defines.get(name).map_or(false, |v| v == value);
+                            ctx.conditions.push(Condition::new(ctx.line, taken, active));
+                        }
+                        ConditionStatement::Else => match ctx.conditions.last_mut() {
+                            Some(ref mut c) if !c.in_else => c.in_else = true,
+                            Some(_) => return Some(Err(FoggyError::at(
+                                ErrorKind::Syntax(String::from("/else after /else")), &ctx.filename, ctx.line, 1))),
+                            None => return Some(Err(FoggyError::at(
+                                ErrorKind::Syntax(String::from("/else without /if")), &ctx.filename, ctx.line, 1))),
+                        },
+                        ConditionStatement::EndIf => {
+                            if ctx.conditions.pop().is_none() {
+                                return Some(Err(FoggyError::at(
+                                    ErrorKind::Syntax(String::from("/endif without /if")), &ctx.filename, ctx.line, 1)));
+                            }
+                        }
+                    }
+                    continue;
+                } else if !ctx.is_active() {
+                    // in a block that is ignored
+                    continue;
+                } else if let Some(result) = parse_macro_statement(&line_contents) {
+                    // eg: /macro call(a, b) a -> b : b
+                    // or a multi-line macro:
+                    // /macro call(a, b)
+                    // a -> b : request
+                    // b --> a : response
+                    // /endmacro
+                    let statement = match result {
+                        Err(_) => return Some(Err(FoggyError::at(ErrorKind::InvalidStatement, &ctx.filename, ctx.line, 1))),
+                        Ok(s) => s,
+                    };
+                    match statement {
+                        MacroStatement::Macro(name, params, body) => {
+                            let definition = Macro {
+                                params: params.iter().map(|p| String::from(*p)).collect(),
+                                body: body.iter().map(|b| String::from(*b)).collect(),
+                                file_name: Rc::clone(&ctx.filename),
+                                line: ctx.line,
+                            };
+                            if body.is_some() {
+                                self.macros.insert(String::from(name), Rc::new(definition));
+                            } else {
+                                ctx.macro_definition = Some((String::from(name), definition));
+                            }
+                        }
+                        MacroStatement::EndMacro => return Some(Err(FoggyError::at(
+                            ErrorKind::Syntax(String::from("/endmacro without /macro")), &ctx.filename, ctx.line, 1))),
+                    }
+                    continue;
+                } else if let Some(result) = parse_includecode_statement(&line_contents) {
+                    // include content of file without preprocessing, and with provided header and footer tag
+                    // eg: /includecode example.py tag:python lines:10-40
+                    // eg: /includecode example.py tag:python region:setup
+                    let included = match result {
+                        Err(_) => Err(FoggyError::at(ErrorKind::InvalidStatement, &ctx.filename, ctx.line, 1)),
+                        Ok(statement) => self.include_code(&ctx, &statement),
+                    };
+                    if let Err(e) = included {
+                        return Some(Err(e.with_location(&ctx.filename, ctx.line, 1)));
+                    }
+                    self.stack.push(ctx);
+                    return self.pending.pop_front().map(Ok);
                 } else if let Some(result) = parse_import_include_statement(&line_contents) {
                     // import content of file if not imported yet
                     // eg: /import file.fgy
@@ -114,42 +737,83 @@ impl<'a> Iterator for Preprocessor<'a> {
                     // eg: /include file.fgy
 
                     match result {
-                        Err(s) => return Some(Err(s)),
-                        Ok((token, file)) => {
+                        Err(_) => return Some(Err(FoggyError::at(ErrorKind::InvalidStatement, &ctx.filename, ctx.line, 1))),
+                        Ok((token, name)) => {
+                            // file names are relative to the file that imports them
+                            let resolved = match self.input.resolve(name, Some(&ctx.filename)) {
+                                Ok(f) => f,
+                                Err(e) => return Some(Err(e.with_location(&ctx.filename, ctx.line, 1))),
+                            };
+                            let file = resolved.as_str();
                             if token == "/import" && self.file_already_processed(file) {
                                 //println!("ignore import of {}", file);
                                 //File already imported, nothing to do, ignore line
                                 continue;
                             }
 
+                            // /include ignores processed_files, a file that includes itself would never end
+                            if let Err(kind) = self.check_include(&ctx, file) {
+                                return Some(Err(FoggyError::at(kind, &ctx.filename, ctx.line, 1)));
+                            }
+
                             self.processed_files.push(String::from(file));
                             match self.input.get_data(file) {
                                 Ok(d) => {
                                     // File exists, push new file to stack and iter one more tine
                                     //println!("importing {} ({})", file, token);
+                                    // an imported file has its own namespace, an included file is just more text
+                                    let namespace = match token {
+                                        "/import" => Rc::new(import_namespace(&ctx.namespace, name)),
+                                        _ => Rc::clone(&ctx.namespace),
+                                    };
                                     self.stack.push(ctx);
                                     self.stack.push(StackContext {
                                         lines: d.lines(),
                                         filename: Rc::new(String::from(file)),
-                                        namespace: Rc::new(String::from(file)),
+                                        namespace,
+                                        line: 0,
+                                        conditions: Vec::new(),
+                                        macro_definition: None,
+                                        fence: None,
                                     });
                                     break;
                                 }
                                 // File does not exist, return am error
-                                Err(s) => return Some(Err(s)),
+                                Err(e) => return Some(Err(e.with_location(&ctx.filename, ctx.line, 1))),
                             }
                         }
                     }
-                } else if line_contents.starts_with("/includecode") {
-                    // include content of file without preprocessing, and with provided header and footer tag
-                    // eg: /includecode example.py tag:pythoncode
                 } else {
-                    // Normal line without specific treatment
+                    // Normal line, replace ${NAME} and expand macro calls
+                    let text = match substitute_defines(&line_contents, &self.defines) {
+                        Ok(t) => t,
+                        Err((offset, kind)) => return Some(Err(FoggyError::at(kind, &ctx.filename, ctx.line, offset as u32 + 1))),
+                    };
+                    if let Some((name, args, rem)) = parse_call(text.trim()) {
+                        if rem.trim().len() == 0 && self.macros.contains_key(name) {
+                            if let Err(e) = self.expand_macro(&ctx, name, &args) {
+                                return Some(Err(e));
+                            }
+                            self.stack.push(ctx);
+                            // a macro can be empty, look for the next line then
+                            return match self.pending.pop_front() {
+                                Some(l) => Some(Ok(l)),
+                                None => self.next(),
+                            };
+                        }
+                    }
+
+                    ctx.fence = match ctx.fence {
+                        Some((fence, _)) if is_fence_end(&text, fence) => None,
+                        Some(f) => Some(f),
+                        None => fence_start(&text),
+                    };
                     result = Some(Ok(LineWithContext {
-                        text: line_contents.clone(),
-                        line: 0,
+                        text,
+                        line: ctx.line,
                         file_name: Rc::clone(&ctx.filename),
                         namespace: Rc::clone(&ctx.namespace),
+                        note: None,
                     }));
 
                     self.stack.push(ctx);
@@ -164,6 +828,7 @@ impl<'a> Iterator for Preprocessor<'a> {
 #[cfg(test)]
 mod tests {
     use super::Datasource;
+    use crate::errors::{ErrorKind, FoggyError};
     use super::LineWithContext;
     use super::Preprocessor;
     use std::io::BufRead;
@@ -174,7 +839,7 @@ mod tests {
     // Stub datasource that provides file contents for tests
     struct SimpleTestDataSource {}
     impl Datasource for SimpleTestDataSource {
-        fn get_data(&self, filename: &str) -> Result<Box<dyn BufRead>, &'static str> {
+        fn get_data(&self, filename: &str) -> Result<Box<dyn BufRead>, FoggyError> {
             match filename {
                 "utf8" => Ok(Box::new(Cursor::new("你好".as_bytes()))),
                 "test_1line" => Ok(Box::new(Cursor::new("content".as_bytes()))),
@@ -195,7 +860,85 @@ mod tests {
                 "file_with_bad_include" => Ok(Box::new(Cursor::new(
                     "/include doesnotcompute.txt".as_bytes(),
                 ))),
-                _ => Err("File does not exist"),
+                "file_with_comments" => Ok(Box::new(Cursor::new(
+                    "// comment\n/import imported_file\ncontent1\n\n/import imported_file\ncontent2".as_bytes(),
+                ))),
+                "file_with_late_bad_include" => Ok(Box::new(Cursor::new(
+                    "content1\n// comment\n/include doesnotcompute.txt".as_bytes(),
+                ))),
+                "file_with_late_bad_import" => Ok(Box::new(Cursor::new(
+                    "content1\n\n// comment\n/import a b".as_bytes(),
+                ))),
+                "file_with_conditions" => Ok(Box::new(Cursor::new(
+                    "/define EDITION public\n/ifdef INTERNAL\ninternal\n/else\n/if EDITION == public\npublic\n/endif\n/endif\n/include defines\n/ifdef FROM_INCLUDE\nincluded\n/endif".as_bytes(),
+                ))),
+                "defines" => Ok(Box::new(Cursor::new(
+                    "/ifdef INTERNAL\n/define FROM_INCLUDE\n/endif".as_bytes(),
+                ))),
+                "file_with_unclosed_if" => Ok(Box::new(Cursor::new(
+                    "content1\n/ifdef A\n/if B == c\n/endif\ncontent2".as_bytes(),
+                ))),
+                "file_with_unclosed_include" => Ok(Box::new(Cursor::new(
+                    "/ifdef A\n/include unclosed_if\n/endif".as_bytes(),
+                ))),
+                "unclosed_if" => Ok(Box::new(Cursor::new(
+                    "/ifdef A".as_bytes(),
+                ))),
+                "file_with_double_else" => Ok(Box::new(Cursor::new(
+                    "/ifdef A\n/else\n/else\n/endif".as_bytes(),
+                ))),
+                "file_with_lonely_endif" => Ok(Box::new(Cursor::new(
+                    "content1\n/endif".as_bytes(),
+                ))),
+                "file_with_includecode" => Ok(Box::new(Cursor::new(
+                    "text\n/includecode code.py tag:python lines:2-3\n/includecode code.py tag:python region:main".as_bytes(),
+                ))),
+                "file_with_bad_includecode" => Ok(Box::new(Cursor::new(
+                    "text\n/includecode code.py tag:python region:nothere".as_bytes(),
+                ))),
+                "code.py" => Ok(Box::new(Cursor::new(
+                    "import os\n/include not_preprocessed\n// not a comment\n# @region main\nprint(\"```\")\n# @endregion main".as_bytes(),
+                ))),
+                "file_with_macros" => Ok(Box::new(Cursor::new(
+                    "/define SERVER backend\n/macro call(a, b) a -> b : b\n/macro exchange(client)\nclient -> ${SERVER} : request\n${SERVER} --> client : response\n/endmacro\ncall(alice, bob)\nexchange( alice )\ntext ${SERVER}\n/macro nothing()\n/endmacro\nnothing()\nbob(alice)".as_bytes(),
+                ))),
+                "file_with_undefined" => Ok(Box::new(Cursor::new(
+                    "content1\ntext ${NOTHERE}".as_bytes(),
+                ))),
+                "file_with_code_fence" => Ok(Box::new(Cursor::new(
+                    "/define SERVER backend\n/macro call(a) a -> ${SERVER}\n```sh\nexport PATH=${SERVER}/bin\ncall(alice)\n// keep this comment\n/define SERVER other\n/include imported_file\n/macro call(a) a -> other\n````\n```sequence\ncall(alice)\n```\nUse `${SERVER}` or $${SERVER}".as_bytes(),
+                ))),
+                "file_with_bad_call" => Ok(Box::new(Cursor::new(
+                    "/macro call(a, b) a -> b\ncall(alice)".as_bytes(),
+                ))),
+                "file_with_unclosed_macro" => Ok(Box::new(Cursor::new(
+                    "content1\n/macro call(a)\na -> b".as_bytes(),
+                ))),
+                "file_with_namespaces" => Ok(Box::new(Cursor::new(
+                    "top\n/import dir/lib.fgu\n/include inc".as_bytes(),
+                ))),
+                "dir/lib.fgu" => Ok(Box::new(Cursor::new(
+                    "in lib\n/include inc\n/import sub.fgu".as_bytes(),
+                ))),
+                "sub.fgu" => Ok(Box::new(Cursor::new(
+                    "in sub".as_bytes(),
+                ))),
+                "inc" => Ok(Box::new(Cursor::new(
+                    "included".as_bytes(),
+                ))),
+                "cycle_a" => Ok(Box::new(Cursor::new(
+                    "/include cycle_b".as_bytes(),
+                ))),
+                "cycle_b" => Ok(Box::new(Cursor::new(
+                    "line b\n/include cycle_a".as_bytes(),
+                ))),
+                "cycle_self" => Ok(Box::new(Cursor::new(
+                    "/include cycle_self".as_bytes(),
+                ))),
+                "not_utf8" => Ok(Box::new(Cursor::new(
+                    &b"content1\n\xff\xfe"[..],
+                ))),
+                _ => Err(FoggyError::new(ErrorKind::Io(String::from("File does not exist")))),
             }
         }
     }
@@ -205,7 +948,7 @@ mod tests {
         let source = SimpleTestDataSource {};
         let pre = Preprocessor::new(&source, &["test_1line"]);
 
-        let output: Vec<Result<LineWithContext, &'static str>> = pre.collect();
+        let output: Vec<Result<LineWithContext, FoggyError>> = pre.collect();
         assert_eq!(output.len(), 1);
         assert!(!output[0].is_err());
         let t = &output[0];
@@ -218,7 +961,7 @@ mod tests {
         let source = SimpleTestDataSource {};
         let pre = Preprocessor::new(&source, &["test_3lines"]);
 
-        let output: Vec<Result<LineWithContext, &'static str>> = pre.collect();
+        let output: Vec<Result<LineWithContext, FoggyError>> = pre.collect();
         assert_eq!(output.len(), 3);
         assert!(!output[0].is_err());
         assert!(!output[1].is_err());
@@ -234,7 +977,7 @@ mod tests {
         let source = SimpleTestDataSource {};
         let pre = Preprocessor::new(&source, &["file_with_import"]);
 
-        let output: Vec<Result<LineWithContext, &'static str>> = pre.collect();
+        let output: Vec<Result<LineWithContext, FoggyError>> = pre.collect();
         assert_eq!(output.len(), 5);
 
         for out in output {
@@ -247,7 +990,7 @@ mod tests {
         let source = SimpleTestDataSource {};
         let pre = Preprocessor::new(&source, &["file_with_include"]);
 
-        let output: Vec<Result<LineWithContext, &'static str>> = pre.collect();
+        let output: Vec<Result<LineWithContext, FoggyError>> = pre.collect();
         assert_eq!(output.len(), 7);
     }
 
@@ -256,11 +999,360 @@ mod tests {
         let source = SimpleTestDataSource {};
         let pre = Preprocessor::new(&source, &["file_with_bad_include"]);
 
-        let output: Vec<Result<LineWithContext, &'static str>> = pre.collect();
+        let output: Vec<Result<LineWithContext, FoggyError>> = pre.collect();
         assert_eq!(output.len(), 1);
         assert!(output[0].is_err());
     }
 
+    #[test]
+    fn test_preproc_line_numbers() {
+        let source = SimpleTestDataSource {};
+        let pre = Preprocessor::new(&source, &["file_with_comments"]);
+
+        let output: Vec<(String, String, u32)> = pre
+            .map(|l| l.unwrap())
+            .map(|l| (l.text, l.file_name.to_string(), l.line))
+            .collect();
+        assert_eq!(
+            output,
+            vec![
+                (String::from("imported line 1"), String::from("imported_file"), 1),
+                (String::from("imported line 2"), String::from("imported_file"), 2),
+                (String::from("content1"), String::from("file_with_comments"), 3),
+                (String::from(""), String::from("file_with_comments"), 4),
+                (String::from("content2"), String::from("file_with_comments"), 6),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_preproc_error_line_numbers() {
+        let source = SimpleTestDataSource {};
+        let mut pre = Preprocessor::new(&source, &["file_with_late_bad_include"]);
+        assert_eq!(pre.next().unwrap().unwrap().line, 1);
+        assert_eq!(
+            pre.next().unwrap().unwrap_err().to_string(),
+            "file_with_late_bad_include:3:1: File does not exist"
+        );
+
+        let mut pre = Preprocessor::new(&source, &["file_with_late_bad_import"]);
+        assert_eq!(pre.next().unwrap().unwrap().line, 1);
+        assert_eq!(pre.next().unwrap().unwrap().line, 2);
+        assert_eq!(
+            pre.next().unwrap().unwrap_err().to_string(),
+            "file_with_late_bad_import:4:1: invalid statement"
+        );
+
+        let output: Vec<Result<LineWithContext, FoggyError>> =
+            Preprocessor::new(&source, &["not_utf8"]).collect();
+        assert_eq!(output.len(), 2);
+        let error = output[1].as_ref().unwrap_err();
+        assert_eq!((error.file_name.as_ref().unwrap().as_str(), error.line), ("not_utf8", 2));
+    }
+
+    fn texts(output: Vec<Result<LineWithContext, FoggyError>>) -> Vec<String> {
+        output.into_iter().map(|l| l.unwrap().text).collect()
+    }
+
+    #[test]
+    fn test_preproc_conditions() {
+        let source = SimpleTestDataSource {};
+
+        let pre = Preprocessor::new(&source, &["file_with_conditions"]);
+        assert_eq!(texts(pre.collect()), vec!["public"]);
+
+        // defines from the API, and defines from an included file
+        let mut pre = Preprocessor::new(&source, &["file_with_conditions"]);
+        pre.define("INTERNAL", "");
+        assert_eq!(texts(pre.collect()), vec!["internal", "included"]);
+
+        // /define in the file overrides the API
+        let mut pre = Preprocessor::new(&source, &["file_with_conditions"]);
+        pre.define("EDITION", "internal");
+        assert_eq!(texts(pre.collect()), vec!["public"]);
+    }
+
+    #[test]
+    fn test_preproc_condition_errors() {
+        let source = SimpleTestDataSource {};
+        let error = |file: &str| {
+            let mut pre = Preprocessor::new(&source, &[file]);
+            pre.define("A", "");
+            return pre.find(|l| l.is_err()).unwrap().unwrap_err().to_string();
+        };
+
+        // reported where the block opened
+        assert_eq!(error("file_with_unclosed_if"), "file_with_unclosed_if:2:1: block is not closed");
+        // a block can't continue after the end of an included file
+        assert_eq!(error("file_with_unclosed_include"), "unclosed_if:1:1: block is not closed");
+        assert_eq!(error("file_with_double_else"), "file_with_double_else:3:1: /else after /else");
+        assert_eq!(error("file_with_lonely_endif"), "file_with_lonely_endif:2:1: /endif without /if");
+    }
+
+    #[test]
+    fn test_parse_condition_statement() {
+        use super::parse_condition_statement;
+        use super::ConditionStatement::*;
+
+        assert_eq!(parse_condition_statement("/define A"), Some(Ok(Define("A", ""))));
+        assert_eq!(parse_condition_statement("/define A some value "), Some(Ok(Define("A", "some value"))));
+        assert_eq!(parse_condition_statement("/ifdef A"), Some(Ok(IfDef("A"))));
+        assert_eq!(parse_condition_statement("/if A == b"), Some(Ok(IfEq("A", "b"))));
+        assert_eq!(parse_condition_statement("/if A ==b"), Some(Ok(IfEq("A", "b"))));
+        assert_eq!(parse_condition_statement("/else"), Some(Ok(Else)));
+        assert_eq!(parse_condition_statement("/endif "), Some(Ok(EndIf)));
+        assert_eq!(parse_condition_statement("/import a.fgu"), None);
+        assert_eq!(parse_condition_statement("/iffy"), None);
+        assert_eq!(parse_condition_statement("/define"), Some(Err("Invalid statement")));
+        assert_eq!(parse_condition_statement("/ifdef A B"), Some(Err("Invalid statement")));
+        assert_eq!(parse_condition_statement("/if A"), Some(Err("Invalid statement")));
+        assert_eq!(parse_condition_statement("/if A != b"), Some(Err("Invalid statement")));
+        assert_eq!(parse_condition_statement("/endif A"), Some(Err("Invalid statement")));
+    }
+
+    #[test]
+    fn test_preproc_includecode() {
+        let source = SimpleTestDataSource {};
+        let pre = Preprocessor::new(&source, &["file_with_includecode"]);
+
+        let output: Vec<(String, String, u32)> = pre
+            .map(|l| l.unwrap())
+            .map(|l| (l.text, l.file_name.to_string(), l.line))
+            .collect();
+        let line = |text: &str, file: &str, number: u32| (String::from(text), String::from(file), number);
+        assert_eq!(
+            output,
+            vec![
+                line("text", "file_with_includecode", 1),
+                line("```python", "file_with_includecode", 2),
+                line("/include not_preprocessed", "code.py", 2),
+                line("// not a comment", "code.py", 3),
+                line("```", "file_with_includecode", 2),
+                // the fence is longer than the backticks in the code
+                line("````python", "file_with_includecode", 3),
+                line("print(\"```\")", "code.py", 5),
+                line("````", "file_with_includecode", 3),
+            ]
+        );
+
+        let output: Vec<Result<LineWithContext, FoggyError>> =
+            Preprocessor::new(&source, &["file_with_bad_includecode"]).collect();
+        assert_eq!(
+            output[1].as_ref().unwrap_err().to_string(),
+            "file_with_bad_includecode:2:1: region 'nothere' not found in 'code.py'"
+        );
+    }
+
+    #[test]
+    fn test_select_code_lines() {
+        use super::{parse_includecode_statement, select_code_lines};
+        use std::rc::Rc;
+
+        let file_name = Rc::new(String::from("code.py"));
+        let lines: Vec<String> = ["a", "# @region r1", "b", "// @region r2", "c", "// @endregion r2", "d"]
+            .iter().map(|l| l.to_string()).collect();
+        let select = |statement: &str| {
+            let statement = parse_includecode_statement(statement).unwrap().unwrap();
+            select_code_lines(&lines, &statement, &file_name).map_err(|e| e.to_string())
+        };
+
+        assert_eq!(select("/includecode f tag:t lines:7"), Ok(vec![(7, String::from("d"))]));
+        assert_eq!(select("/includecode f tag:t region:r2"), Ok(vec![(5, String::from("c"))]));
+        // markers are removed from the output
+        assert_eq!(
+            select("/includecode f tag:t lines:1-5"),
+            Ok(vec![(1, String::from("a")), (3, String::from("b")), (5, String::from("c"))])
+        );
+        assert_eq!(select("/includecode f tag:t lines:6-8"), Err(String::from("lines 6-8 out of range, 'code.py' has 7 lines")));
+        assert_eq!(select("/includecode f tag:t region:r1"), Err(String::from("code.py:2:1: block is not closed")));
+    }
+
+    #[test]
+    fn test_parse_includecode_statement() {
+        use super::{parse_includecode_statement, IncludeCodeStatement};
+
+        assert_eq!(
+            parse_includecode_statement("/includecode a.py tag:python"),
+            Some(Ok(IncludeCodeStatement { file: "a.py", tag: "python", lines: None, region: None }))
+        );
+        assert_eq!(
+            parse_includecode_statement("/includecode a.py lines:10-40 tag:python"),
+            Some(Ok(IncludeCodeStatement { file: "a.py", tag: "python", lines: Some((10, 40)), region: None }))
+        );
+        assert_eq!(
+            parse_includecode_statement("/includecode a.py tag:python region:main "),
+            Some(Ok(IncludeCodeStatement { file: "a.py", tag: "python", lines: None, region: Some("main") }))
+        );
+        assert_eq!(parse_includecode_statement("/include a.py"), None);
+        assert_eq!(parse_includecode_statement("/includecode"), Some(Err("Invalid statement")));
+        assert_eq!(parse_includecode_statement("/includecode a.py"), Some(Err("Invalid statement")));
+        assert_eq!(parse_includecode_statement("/includecode a.py tag:"), Some(Err("Invalid statement")));
+        assert_eq!(parse_includecode_statement("/includecode a.py tag:a tag:b"), Some(Err("Invalid statement")));
+        assert_eq!(parse_includecode_statement("/includecode a.py tag:a lines:0-3"), Some(Err("Invalid statement")));
+        assert_eq!(parse_includecode_statement("/includecode a.py tag:a lines:5-3"), Some(Err("Invalid statement")));
+        assert_eq!(parse_includecode_statement("/includecode a.py tag:a lines:x"), Some(Err("Invalid statement")));
+        assert_eq!(parse_includecode_statement("/includecode a.py tag:a lines:1-2 region:r"), Some(Err("Invalid statement")));
+        assert_eq!(parse_includecode_statement("/includecode a.py tag:a other"), Some(Err("Invalid statement")));
+    }
+
+    #[test]
+    fn test_preproc_macros() {
+        let source = SimpleTestDataSource {};
+        let pre = Preprocessor::new(&source, &["file_with_macros"]);
+
+        let output: Vec<(String, u32, Option<String>)> = pre
+            .map(|l| l.unwrap())
+            .map(|l| (l.text, l.line, l.note.map(|n| n.to_string())))
+            .collect();
+        let call = Some(String::from("expanded from macro 'call' defined at file_with_macros:2"));
+        let exchange = Some(String::from("expanded from macro 'exchange' defined at file_with_macros:3"));
+        assert_eq!(
+            output,
+            vec![
+                (String::from("alice -> bob : bob"), 7, call),
+                (String::from("alice -> backend : request"), 8, exchange.clone()),
+                (String::from("backend --> alice : response"), 8, exchange),
+                (String::from("text backend"), 9, None),
+                // not a macro
+                (String::from("bob(alice)"), 13, None),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_preproc_undefined_and_code() {
+        let source = SimpleTestDataSource {};
+        let text = |file: &str| -> Vec<String> {
+            Preprocessor::new(&source, &[file]).map(|l| l.unwrap().text).collect()
+        };
+
+        // undefined names are not an error, they are just text
+        assert_eq!(text("file_with_undefined"), vec!["content1", "text ${NOTHERE}"]);
+        // fenced code is not preprocessed, fenced diagrams are
+        assert_eq!(
+            text("file_with_code_fence"),
+            vec![
+                "```sh",
+                "export PATH=${SERVER}/bin",
+                "call(alice)",
+                "// keep this comment",
+                "/define SERVER other",
+                "/include imported_file",
+                "/macro call(a) a -> other",
+                "````",
+                "```sequence",
+                "alice -> backend",
+                "```",
+                "Use `backend` or ${SERVER}",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_preproc_macro_errors() {
+        let source = SimpleTestDataSource {};
+        let error = |file: &str| {
+            let mut pre = Preprocessor::new(&source, &[file]);
+            return pre.find(|l| l.is_err()).unwrap().unwrap_err().to_string();
+        };
+
+        assert_eq!(error("file_with_bad_call"), "file_with_bad_call:2:1: macro 'call' expects 2 arguments, got 1");
+        assert_eq!(error("file_with_unclosed_macro"), "file_with_unclosed_macro:2:1: block is not closed");
+    }
+
+    #[test]
+    fn test_substitutions() {
+        use super::{substitute_defines, substitute_words};
+        use std::collections::HashMap;
+
+        let params = vec![String::from("a"), String::from("b")];
+        assert_eq!(substitute_words("a -> b : b", &params, &["alice", "bob"]), "alice -> bob : bob");
+        assert_eq!(substitute_words("a->bb:(b_)", &params, &["alice", "bob"]), "alice->bb:(b_)");
+        assert_eq!(substitute_words("", &params, &["alice", "bob"]), "");
+
+        let mut defines = HashMap::new();
+        defines.insert(String::from("A"), String::from("alice"));
+        assert_eq!(substitute_defines("${A} -> ${A}", &defines), Ok(String::from("alice -> alice")));
+        assert_eq!(substitute_defines("$A {A}", &defines), Ok(String::from("$A {A}")));
+        assert_eq!(substitute_defines("a ${A", &defines).unwrap_err().0, 2);
+        assert_eq!(substitute_defines("${A}${B}", &defines), Ok(String::from("alice${B}")));
+        assert_eq!(substitute_defines("$${A} $$${A} $${", &defines), Ok(String::from("${A} $${A} ${")));
+    }
+
+    #[test]
+    fn test_parse_macro_statement() {
+        use super::parse_macro_statement;
+        use super::MacroStatement::*;
+
+        assert_eq!(parse_macro_statement("/macro call(a, b) a -> b : b"), Some(Ok(Macro("call", vec!["a", "b"], Some("a -> b : b")))));
+        assert_eq!(parse_macro_statement("/macro call()"), Some(Ok(Macro("call", vec![], None))));
+        assert_eq!(parse_macro_statement("/endmacro"), Some(Ok(EndMacro)));
+        assert_eq!(parse_macro_statement("/macros"), None);
+        assert_eq!(parse_macro_statement("/macro call"), Some(Err("Invalid statement")));
+        assert_eq!(parse_macro_statement("/macro call(a b)"), Some(Err("Invalid statement")));
+        assert_eq!(parse_macro_statement("/macro 1call(a)"), Some(Err("Invalid statement")));
+        assert_eq!(parse_macro_statement("/endmacro call"), Some(Err("Invalid statement")));
+    }
+
+    #[test]
+    fn test_preproc_namespaces() {
+        let source = SimpleTestDataSource {};
+        let pre = Preprocessor::new(&source, &["file_with_namespaces"]);
+
+        let output: Vec<(String, String)> = pre
+            .map(|l| l.unwrap())
+            .map(|l| (l.text, l.namespace.to_string()))
+            .collect();
+        let line = |text: &str, namespace: &str| (String::from(text), String::from(namespace));
+        assert_eq!(
+            output,
+            vec![
+                line("top", ""),
+                line("in lib", "lib"),
+                // included files stay in the namespace of the file that includes them
+                line("included", "lib"),
+                line("in sub", "lib.sub"),
+                line("included", ""),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_preproc_circular_include() {
+        let source = SimpleTestDataSource {};
+        let error = |file: &str| {
+            let mut pre = Preprocessor::new(&source, &[file]);
+            return pre.find(|l| l.is_err()).unwrap().unwrap_err();
+        };
+
+        let e = error("cycle_a");
+        assert_eq!(e.to_string(), "cycle_b:2:1: circular include: cycle_a → cycle_b → cycle_a");
+        assert_eq!(e.kind, ErrorKind::CircularInclude(vec![
+            String::from("cycle_a"), String::from("cycle_b"), String::from("cycle_a")]));
+        assert_eq!(error("cycle_self").to_string(), "cycle_self:1:1: circular include: cycle_self → cycle_self");
+
+        // the same file can still be included several times, as long as it's not in the chain
+        let output: Vec<Result<LineWithContext, FoggyError>> =
+            Preprocessor::new(&source, &["file_with_include"]).collect();
+        assert!(output.iter().all(|l| l.is_ok()));
+    }
+
+    #[test]
+    fn test_preproc_max_include_depth() {
+        let source = SimpleTestDataSource {};
+
+        let mut pre = Preprocessor::new(&source, &["file_with_include"]);
+        pre.set_max_include_depth(2);
+        assert!(pre.all(|l| l.is_ok()));
+
+        let mut pre = Preprocessor::new(&source, &["file_with_include"]);
+        pre.set_max_include_depth(1);
+        assert_eq!(
+            pre.next().unwrap().unwrap_err().to_string(),
+            "file_with_include:1:1: too many nested includes (maximum is 1)"
+        );
+    }
+
     #[test]
     fn test_parse_import_include_statement() {
         assert_eq!(
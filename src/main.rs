@@ -1,42 +1,392 @@
 extern crate foggy_engine;
 
-// For testing
-use std::io::Cursor;
-use std::io::BufRead;
+use std::collections::HashSet;
+use std::env;
+use std::fs;
+use std::io::{self, BufRead, Cursor, Read, Write};
+use std::path::{Path, PathBuf};
+use std::process;
+use std::rc::Rc;
 
 use foggy_engine::datatypes::*;
-use foggy_engine::parsers::datatypes::*;
-use foggy_engine::parsers::sequenceparser::SequenceDiagramParser;
+use foggy_engine::parsers::parsercombinator::ParserCombinator;
 use foggy_engine::builders::sequencebuilder::SequenceDiagramBuilder;
 use foggy_engine::preprocessor;
+use foggy_engine::preprocessor::Datasource;
+use foggy_engine::filedatasource::FileDatasource;
+use foggy_engine::errors::FoggyError;
 
-// Test
-struct TestDataSource {}
-impl preprocessor::Datasource for TestDataSource {
-    fn get_data(&self, filename: &str) -> Result<Box<dyn BufRead>, &'static str> {
-        match filename {
-            //"Test" => Ok(Box::new(Cursor::new(self.truc.as_bytes()))),
-            "utf8" => Ok(Box::new(Cursor::new("你好".as_bytes()))),
-            "file.fgu" => Ok(Box::new(Cursor::new(
-                "content\nkw\nrest of content".as_bytes(),
-            ))),
-            "file2" => Ok(Box::new(Cursor::new("pruuuuuuut".as_bytes()))),
-            _ => Err("File does not exist"),
+static USAGE: &str = "Usage: foggy-uml [options] [file...]
+
+Reads .fgu files, or stdin if no file is given or if file is '-'.
+
+Options:
+    -o, --output <path>    write output to this file, or to this directory
+                           (one file per input, or per diagram for svg)
+    -f, --format <format>  output format: svg (default), xml
+    -r, --root <dir>       /import and /include can't read files outside of this directory
+                           (default: directory of the input file, current directory for stdin)
+    -D, --define <name[=value]>
+                           same as '/define name value' at the top of each input
+    --max-include-depth <n>
+                           maximum number of nested /import and /include (default: 32)
+    -h, --help             print this help";
+
+// name used for the data read on stdin
+static STDIN_NAME: &str = "<stdin>";
+
+#[derive(Copy, Clone, PartialEq, Debug)]
+enum OutputFormat {
+    Svg,
+    Xml,
+}
+
+impl OutputFormat {
+    fn extension(self) -> &'static str {
+        match self {
+            OutputFormat::Svg => "svg",
+            OutputFormat::Xml => "xml",
+        }
+    }
+}
+
+#[derive(PartialEq, Debug)]
+struct Options {
+    inputs: Vec<String>,
+    output: Option<PathBuf>,
+    format: OutputFormat,
+    root: Option<PathBuf>,
+    defines: Vec<(String, String)>,
+    max_include_depth: usize,
+    help: bool,
+}
+
+fn parse_args(args: &[String]) -> Result<Options, String> {
+    let mut options = Options {
+        inputs: vec![],
+        output: None,
+        format: OutputFormat::Svg,
+        root: None,
+        defines: vec![],
+        max_include_depth: preprocessor::DEFAULT_MAX_INCLUDE_DEPTH,
+        help: false,
+    };
+
+    let mut iter = args.iter();
+    while let Some(arg) = iter.next() {
+        match arg.as_str() {
+            "-h" | "--help" => options.help = true,
+            "-o" | "--output" => match iter.next() {
+                Some(path) => options.output = Some(PathBuf::from(path)),
+                None => return Err(format!("missing value for {}", arg)),
+            },
+            "-f" | "--format" => match iter.next().map(|s| s.as_str()) {
+                Some("svg") => options.format = OutputFormat::Svg,
+                Some("xml") => options.format = OutputFormat::Xml,
+                Some(other) => return Err(format!("unknown output format '{}'", other)),
+                None => return Err(format!("missing value for {}", arg)),
+            },
+            "-r" | "--root" => match iter.next() {
+                Some(path) => options.root = Some(PathBuf::from(path)),
+                None => return Err(format!("missing value for {}", arg)),
+            },
+            "-D" | "--define" => match iter.next() {
+                Some(define) => {
+                    let mut parts = define.splitn(2, '=');
+                    let name = parts.next().unwrap_or_default();
+                    if name.is_empty() || name.contains(char::is_whitespace) {
+                        return Err(format!("invalid define '{}'", define));
+                    }
+                    let value = parts.next().unwrap_or_default();
+                    options.defines.push((name.to_string(), value.to_string()));
+                }
+                None => return Err(format!("missing value for {}", arg)),
+            },
+            "--max-include-depth" => match iter.next().map(|s| s.parse::<usize>()) {
+                Some(Ok(depth)) if depth > 0 => options.max_include_depth = depth,
+                Some(_) => return Err(format!("invalid value for {}", arg)),
+                None => return Err(format!("missing value for {}", arg)),
+            },
+            "-" => options.inputs.push(String::from("-")),
+            _ if arg.starts_with('-') => return Err(format!("unknown option '{}'", arg)),
+            _ => options.inputs.push(arg.clone()),
         }
     }
+
+    if options.inputs.is_empty() {
+        options.inputs.push(String::from("-"));
+    }
+    if options.inputs.iter().filter(|i| *i == "-").count() > 1 {
+        return Err(String::from("stdin ('-') can only be read once"));
+    }
+    // documents written one after the other are not a valid file
+    if options.inputs.len() > 1 && options.output.is_none() {
+        return Err(String::from("several inputs can't be written to stdout, use -o <dir>"));
+    }
+    Ok(options)
+}
+
+// Files are read from the filesystem, stdin is read beforehand and served under STDIN_NAME
+struct CliDataSource {
+    files: FileDatasource,
+    stdin: Option<String>,
+}
+
+impl Datasource for CliDataSource {
+    fn resolve(&self, filename: &str, parent: Option<&str>) -> Result<String, FoggyError> {
+        if parent.is_none() && filename == STDIN_NAME {
+            return Ok(String::from(filename));
+        }
+        self.files.resolve(filename, parent)
+    }
+
+    fn get_data(&self, filename: &str) -> Result<Box<dyn BufRead>, FoggyError> {
+        if filename == STDIN_NAME {
+            if let Some(ref text) = self.stdin {
+                return Ok(Box::new(Cursor::new(text.clone().into_bytes())));
+            }
+        }
+        self.files.get_data(filename)
+    }
+}
+
+// One rendered output for an input file: a name suffix and its content
+struct Output {
+    suffix: String,
+    content: String,
+}
+
+fn render(documents: &[Document], format: OutputFormat) -> Result<Vec<Output>, FoggyError> {
+    match format {
+        OutputFormat::Xml => {
+            let mut xml = String::new();
+            for d in documents {
+                for elt in &d.children {
+                    xml.push_str(&elt.borrow().to_xml());
+                }
+            }
+            Ok(vec![Output { suffix: String::new(), content: xml }])
+        }
+        OutputFormat::Svg => {
+            // Diagrams are either documents on their own or embedded in text,
+            // look for header/content pairs anywhere in the trees
+            let mut diagram_parts: Vec<Rcc<Element>> = vec![];
+            for d in documents {
+                for elt in &d.children {
+                    recurse_element_tree(Rc::clone(elt), |e, _d| {
+                        let is_diagram_part = {
+                            let elt = e.borrow();
+                            elt.is_tree()
+                                && (elt.get_tag() == "sequencediagram:header"
+                                    || elt.get_tag() == "sequencediagram:content")
+                        };
+                        if is_diagram_part {
+                            diagram_parts.push(e);
+                        }
+                    });
+                }
+            }
+
+            let diagram_count = diagram_parts.len() / 2;
+            let mut outputs = vec![];
+            for (i, diagram) in diagram_parts.chunks(2).enumerate() {
+                let mut builder = SequenceDiagramBuilder::new();
+                let svg = builder.generate_svg(diagram)?;
+                let suffix = if diagram_count > 1 { format!("-{}", i + 1) } else { String::new() };
+                outputs.push(Output { suffix, content: svg });
+            }
+            Ok(outputs)
+        }
+    }
+}
+
+// written holds the files written for the previous inputs, an input shall not overwrite them
+fn process_input(input: &str, options: &Options, written: &mut HashSet<PathBuf>) -> Result<(), String> {
+    let (source, name, stem) = if input == "-" {
+        let mut text = String::new();
+        io::stdin().read_to_string(&mut text).map_err(|e| format!("{}: {}", STDIN_NAME, e))?;
+        let root = options.root.clone().unwrap_or_else(|| PathBuf::from("."));
+        let files = FileDatasource::new(&root).map_err(|e| e.to_string())?;
+        let source = CliDataSource { files, stdin: Some(text) };
+        (source, String::from(STDIN_NAME), String::from("stdin"))
+    } else {
+        let path = Path::new(input).canonicalize().map_err(|e| format!("{}: {}", input, e))?;
+        let stem = path.file_stem().map(|s| s.to_string_lossy().into_owned()).unwrap_or_default();
+        let root = match options.root {
+            Some(ref r) => r.clone(),
+            None => path.parent().map(|p| p.to_path_buf()).unwrap_or_default(),
+        };
+        let files = FileDatasource::new(&root).map_err(|e| e.to_string())?;
+        // the preprocessor wants the input file name relative to the root
+        let name = match path.strip_prefix(files.get_root()) {
+            Ok(n) => n.to_string_lossy().into_owned(),
+            Err(_) => return Err(format!("{}: file is outside of the root directory {}", input, root.display())),
+        };
+        (CliDataSource { files, stdin: None }, name, stem)
+    };
+
+    // errors without location are about the input file as a whole
+    let file_name = Rc::new(name.clone());
+    let mut pre = preprocessor::Preprocessor::new(&source, &[name.as_str()]);
+    for (define, value) in &options.defines {
+        pre.define(define, value);
+    }
+    pre.set_max_include_depth(options.max_include_depth);
+    let documents = ParserCombinator::interpret(&mut pre)
+        .map_err(|e| e.with_location(&file_name, 0, 0).to_string())?;
+    let outputs = render(&documents, options.format)
+        .map_err(|e| e.with_location(&file_name, 0, 0).to_string())?;
+    if outputs.is_empty() {
+        return Err(format!("{}: nothing to render", name));
+    }
+
+    match options.output {
+        None => {
+            if outputs.len() > 1 {
+                return Err(format!("{}: {} diagrams can't be written to stdout, use -o <dir>", name, outputs.len()));
+            }
+            let stdout = io::stdout();
+            let mut handle = stdout.lock();
+            for o in &outputs {
+                handle.write_all(o.content.as_bytes()).map_err(|e| e.to_string())?;
+            }
+        }
+        Some(ref path) => {
+            let to_dir = path.is_dir() || options.inputs.len() > 1 || outputs.len() > 1;
+            if to_dir && !path.is_dir() {
+                return Err(format!("{}: several outputs, {} shall be a directory", name, path.display()));
+            }
+            let file_paths: Vec<PathBuf> = outputs.iter().map(|o| if to_dir {
+                path.join(format!("{}{}.{}", stem, o.suffix, options.format.extension()))
+            } else {
+                path.clone()
+            }).collect();
+            if let Some(file_path) = file_paths.iter().find(|p| written.contains(*p)) {
+                return Err(format!("{}: {} was already written for an other input", name, file_path.display()));
+            }
+            for (o, file_path) in outputs.iter().zip(file_paths) {
+                fs::write(&file_path, &o.content)
+                    .map_err(|e| format!("{}: {}", file_path.display(), e))?;
+                written.insert(file_path);
+            }
+        }
+    }
+    Ok(())
 }
 
 fn main() {
-    // let mut source = TestDataSource {};
-    // let mut pre = preprocessor::Preprocessor::new(&source, &["utf8", "utf8", "file.fgu"]);
-
-    let mut parser = SequenceDiagramParser::new();
-    parser.step(& mut SliceWithContext::new_for_tests(&"alice->bob : Hello"));
-    parser.step(& mut SliceWithContext::new_for_tests(&"alice<-bob : ..."));
-    parser.step(& mut SliceWithContext::new_for_tests(&"bob->eve : !!!"));
-
-    let (elements, _documents) = parser.flush();
-    let mut builder = SequenceDiagramBuilder::new();
-    let xml = builder.generate_svg(&elements);
-    println!("{}", xml.unwrap())
+    let args: Vec<String> = env::args().skip(1).collect();
+    let options = match parse_args(&args) {
+        Ok(o) => o,
+        Err(e) => {
+            eprintln!("foggy-uml: {}\n\n{}", e, USAGE);
+            process::exit(2);
+        }
+    };
+    if options.help {
+        println!("{}", USAGE);
+        return;
+    }
+
+    let mut failed = false;
+    let mut written = HashSet::new();
+    for input in &options.inputs {
+        if let Err(e) = process_input(input, &options, &mut written) {
+            eprintln!("foggy-uml: {}", e);
+            failed = true;
+        }
+    }
+    if failed {
+        process::exit(1);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(list: &[&str]) -> Vec<String> {
+        list.iter().map(|s| s.to_string()).collect()
+    }
+
+    #[test]
+    fn test_parse_args_default() {
+        let options = parse_args(&args(&[])).unwrap();
+        assert_eq!(options.inputs, vec![String::from("-")]);
+        assert_eq!(options.output, None);
+        assert_eq!(options.format, OutputFormat::Svg);
+    }
+
+    #[test]
+    fn test_parse_args_full() {
+        let options = parse_args(&args(&["-f", "xml", "a.fgu", "-o", "out", "b.fgu", "-r", "docs"])).unwrap();
+        assert_eq!(options.inputs, args(&["a.fgu", "b.fgu"]));
+        assert_eq!(options.output, Some(PathBuf::from("out")));
+        assert_eq!(options.root, Some(PathBuf::from("docs")));
+        assert_eq!(options.format, OutputFormat::Xml);
+        assert_eq!(options.max_include_depth, preprocessor::DEFAULT_MAX_INCLUDE_DEPTH);
+
+        let options = parse_args(&args(&["--max-include-depth", "4"])).unwrap();
+        assert_eq!(options.max_include_depth, 4);
+    }
+
+    #[test]
+    fn test_parse_args_defines() {
+        let options = parse_args(&args(&["-D", "INTERNAL", "--define", "EDITION=public", "-D", "A=b=c"])).unwrap();
+        assert_eq!(options.defines, vec![
+            (String::from("INTERNAL"), String::from("")),
+            (String::from("EDITION"), String::from("public")),
+            (String::from("A"), String::from("b=c")),
+        ]);
+        assert!(parse_args(&args(&["-D"])).is_err());
+        assert!(parse_args(&args(&["-D", "=value"])).is_err());
+        assert!(parse_args(&args(&["-D", "A B=c"])).is_err());
+    }
+
+    #[test]
+    fn test_parse_args_errors() {
+        assert!(parse_args(&args(&["-o"])).is_err());
+        assert!(parse_args(&args(&["-r"])).is_err());
+        assert!(parse_args(&args(&["-f", "png"])).is_err());
+        assert!(parse_args(&args(&["--verbose"])).is_err());
+        assert!(parse_args(&args(&["--max-include-depth", "0"])).is_err());
+        assert!(parse_args(&args(&["--max-include-depth", "x"])).is_err());
+        assert!(parse_args(&args(&["-o", "out", "-", "a.fgu", "-"])).is_err());
+        assert!(parse_args(&args(&["a.fgu", "b.fgu"])).is_err());
+    }
+
+    #[test]
+    fn test_render_svg() {
+        let text = "@startuml\nalice->bob : hello\n@enduml\n";
+        let source = CliDataSource {
+            files: FileDatasource::new(Path::new(".")).unwrap(),
+            stdin: Some(String::from(text)),
+        };
+        let mut pre = preprocessor::Preprocessor::new(&source, &[STDIN_NAME]);
+        let documents = ParserCombinator::interpret(&mut pre).unwrap();
+
+        let outputs = render(&documents, OutputFormat::Svg).unwrap();
+        assert_eq!(outputs.len(), 1);
+        assert!(outputs[0].content.starts_with("<svg"));
+    }
+
+    #[test]
+    fn test_output_collision() {
+        let dir = env::temp_dir().join(format!("foggy-uml-collision-{}", process::id()));
+        for sub in &["a", "b", "out"] {
+            fs::create_dir_all(dir.join(sub)).unwrap();
+        }
+        let inputs: Vec<String> = ["a", "b"].iter().map(|sub| {
+            let input = dir.join(sub).join("x.fgu");
+            fs::write(&input, "@startuml\nalice->bob\n@enduml\n").unwrap();
+            input.to_string_lossy().into_owned()
+        }).collect();
+        let options = parse_args(&[inputs[0].clone(), inputs[1].clone(), String::from("-o"), dir.join("out").to_string_lossy().into_owned()]).unwrap();
+
+        let mut written = HashSet::new();
+        assert_eq!(process_input(&inputs[0], &options, &mut written), Ok(()));
+        let error = process_input(&inputs[1], &options, &mut written).unwrap_err();
+        assert!(error.ends_with("x.svg was already written for an other input"));
+        fs::remove_dir_all(&dir).unwrap();
+    }
 }